@@ -0,0 +1,393 @@
+
+use std::cmp::Ordering;
+use std::f64::consts;
+use std::convert::TryFrom;
+
+use crate::bigint::BigInt;
+use crate::tree::{ Term, TermKind, integer, big_integer, float };
+use crate::errors::{ indicator, instantiation_error, type_error, evaluation_error, resource_error };
+
+
+/// A number produced while evaluating an arithmetic expression.  Integers are kept in an i64 when they fit, and are
+/// promoted to a big integer only when an operation overflows
+#[derive(Clone, Debug, PartialEq)]
+pub enum Number {
+    Integer(i64),
+    BigInteger(BigInt),
+    Float(f64),
+}
+
+/// The result of evaluating an expression, or the formal part of the ISO error it raised, like `type_error(...)`
+pub type Evaluation = Result<Number, Term>;
+
+type UnaryFunction = fn(Number) -> Evaluation;
+type BinaryFunction = fn(Number, Number) -> Evaluation;
+
+impl Number {
+    fn from_big(num: BigInt) -> Number {
+        match num.to_i64() {
+            Some(num) => Number::Integer(num),
+            None => Number::BigInteger(num),
+        }
+    }
+
+    fn to_big(&self) -> BigInt {
+        match self {
+            Number::Integer(n) => BigInt::from_i64(*n),
+            Number::BigInteger(n) => n.clone(),
+            Number::Float(n) => BigInt::from_f64(*n).unwrap_or_else(BigInt::zero),
+        }
+    }
+
+    fn to_f64(&self) -> f64 {
+        match self {
+            Number::Integer(n) => *n as f64,
+            Number::BigInteger(n) => n.to_f64(),
+            Number::Float(n) => *n,
+        }
+    }
+
+    fn is_float(&self) -> bool {
+        matches!(self, Number::Float(_))
+    }
+
+    pub fn into_term(self) -> Term {
+        match self {
+            Number::Integer(n) => integer(n),
+            Number::BigInteger(n) => big_integer(n),
+            Number::Float(n) => float(n),
+        }
+    }
+}
+
+/// Evaluate an arithmetic expression, like the right hand side of `is/2`
+pub fn evaluate(term: &Term) -> Evaluation {
+    match &**term {
+        TermKind::Integer(n) => Ok(Number::Integer(*n)),
+        TermKind::BigInteger(n) => Ok(Number::BigInteger(n.clone())),
+        TermKind::Float(n) => Ok(Number::Float(*n)),
+        TermKind::Var(_) => Err(instantiation_error()),
+        TermKind::Atom(name) => lookup_constant(name).ok_or_else(|| type_error("evaluable", indicator(name, 0))),
+        TermKind::Compound(name, args) if args.len() == 1 => {
+            let function = lookup_unary(name).ok_or_else(|| type_error("evaluable", indicator(name, 1)))?;
+            function(evaluate(&args[0])?)
+        },
+        TermKind::Compound(name, args) if args.len() == 2 => {
+            let function = lookup_binary(name).ok_or_else(|| type_error("evaluable", indicator(name, 2)))?;
+            function(evaluate(&args[0])?, evaluate(&args[1])?)
+        },
+        TermKind::Compound(name, args) => Err(type_error("evaluable", indicator(name, args.len()))),
+        TermKind::EmptyList => Err(type_error("evaluable", indicator("[]", 0))),
+        _ => Err(type_error("evaluable", term.clone())),
+    }
+}
+
+/// Evaluate two arithmetic expressions and compare their values, converting an integer to a float if it's being
+/// compared with a float
+pub fn compare(term1: &Term, term2: &Term) -> Result<Ordering, Term> {
+    let (a, b) = (evaluate(term1)?, evaluate(term2)?);
+    Ok(compare_numbers(&a, &b))
+}
+
+fn compare_numbers(a: &Number, b: &Number) -> Ordering {
+    match (a, b) {
+        (Number::Integer(n), Number::Integer(m)) => n.cmp(m),
+        _ if a.is_float() || b.is_float() => a.to_f64().partial_cmp(&b.to_f64()).unwrap_or(Ordering::Equal),
+        _ => a.to_big().cmp(&b.to_big()),
+    }
+}
+
+fn lookup_constant(name: &str) -> Option<Number> {
+    match name {
+        "pi"        => Some(Number::Float(consts::PI)),
+        "e"         => Some(Number::Float(consts::E)),
+        "epsilon"   => Some(Number::Float(f64::EPSILON)),
+        "max_tagged_integer" => Some(Number::Integer(i64::MAX)),
+        _ => None,
+    }
+}
+
+fn lookup_unary(name: &str) -> Option<UnaryFunction> {
+    match name {
+        "-"         => Some(negate),
+        "+"         => Some(Ok),
+        "abs"       => Some(absolute),
+        "sign"      => Some(sign),
+        "\\"        => Some(bitwise_not),
+        "msb"       => Some(most_significant_bit),
+        "sqrt"      => Some(|n| float_function(n, f64::sqrt)),
+        "sin"       => Some(|n| float_function(n, f64::sin)),
+        "cos"       => Some(|n| float_function(n, f64::cos)),
+        "tan"       => Some(|n| float_function(n, f64::tan)),
+        "asin"      => Some(|n| float_function(n, f64::asin)),
+        "acos"      => Some(|n| float_function(n, f64::acos)),
+        "atan"      => Some(|n| float_function(n, f64::atan)),
+        "exp"       => Some(|n| float_function(n, f64::exp)),
+        "log"       => Some(logarithm),
+        "log2"      => Some(|n| float_function(n, f64::log2)),
+        "float"     => Some(|n| float_result(n.to_f64())),
+        "float_integer_part" => Some(|n| float_function(n, f64::trunc)),
+        "float_fractional_part" => Some(|n| float_function(n, f64::fract)),
+        "integer"   => Some(|n| rounding_function(n, f64::round)),
+        "truncate"  => Some(|n| rounding_function(n, f64::trunc)),
+        "round"     => Some(|n| rounding_function(n, f64::round)),
+        "ceiling"   => Some(|n| rounding_function(n, f64::ceil)),
+        "floor"     => Some(|n| rounding_function(n, f64::floor)),
+        _ => None,
+    }
+}
+
+fn lookup_binary(name: &str) -> Option<BinaryFunction> {
+    match name {
+        "+"         => Some(|a, b| integer_or_float(a, b, i64::checked_add, BigInt::add, |n, m| n + m)),
+        "-"         => Some(|a, b| integer_or_float(a, b, i64::checked_sub, BigInt::sub, |n, m| n - m)),
+        "*"         => Some(|a, b| integer_or_float(a, b, i64::checked_mul, BigInt::mul, |n, m| n * m)),
+        "/"         => Some(divide),
+        "//"        => Some(|a, b| integer_only(a, b, i64::checked_div, |n, m| Ok(divide_big(n, m)?.0))),
+        "rem"       => Some(|a, b| integer_only(a, b, i64::checked_rem, |n, m| Ok(divide_big(n, m)?.1))),
+        "mod"       => Some(|a, b| integer_only(a, b, modulo_small, modulo_big)),
+        "div"       => Some(|a, b| integer_only(a, b, floor_divide_small, floor_divide_big)),
+        "min"       => Some(|a, b| Ok(if compare_numbers(&a, &b) == Ordering::Greater { b } else { a })),
+        "max"       => Some(|a, b| Ok(if compare_numbers(&a, &b) == Ordering::Less { b } else { a })),
+        "**"        => Some(|a, b| float_result(a.to_f64().powf(b.to_f64()))),
+        "^"         => Some(power),
+        "atan2" | "atan" => Some(|a, b| float_result(a.to_f64().atan2(b.to_f64()))),
+        "log"       => Some(|a, b| float_result(logarithm(b)?.to_f64() / logarithm(a)?.to_f64())),
+        "/\\"       => Some(|a, b| integer_only(a, b, |n, m| Some(n & m), |n, m| Ok(n.bitwise(m, |x, y| x & y)))),
+        "\\/"       => Some(|a, b| integer_only(a, b, |n, m| Some(n | m), |n, m| Ok(n.bitwise(m, |x, y| x | y)))),
+        "xor"       => Some(|a, b| integer_only(a, b, |n, m| Some(n ^ m), |n, m| Ok(n.bitwise(m, |x, y| x ^ y)))),
+        "<<"        => Some(|a, b| shift(a, b, true)),
+        ">>"        => Some(|a, b| shift(a, b, false)),
+        "gcd"       => Some(|a, b| integer_only(a, b, gcd_small, |n, m| Ok(gcd_big(n, m)))),
+        _ => None,
+    }
+}
+
+/// Check that a float result is a number, raising an error for NaN (eg. `0.0 / 0.0`) or infinity
+fn float_result(num: f64) -> Evaluation {
+    if num.is_nan() {
+        Err(evaluation_error("undefined"))
+    } else if num.is_infinite() {
+        Err(evaluation_error("float_overflow"))
+    } else {
+        Ok(Number::Float(num))
+    }
+}
+
+fn require_integer(num: &Number) -> Result<(), Term> {
+    match num {
+        Number::Float(_) => Err(type_error("integer", num.clone().into_term())),
+        _ => Ok(()),
+    }
+}
+
+/// Apply an operation which gives an integer if both arguments are integers, or a float otherwise.  The small
+/// integer operation is tried first, and the big integer operation is used if it overflows
+fn integer_or_float(
+    a: Number,
+    b: Number,
+    small: fn(i64, i64) -> Option<i64>,
+    big: fn(&BigInt, &BigInt) -> BigInt,
+    float: fn(f64, f64) -> f64,
+) -> Evaluation {
+    match (&a, &b) {
+        _ if a.is_float() || b.is_float() => float_result(float(a.to_f64(), b.to_f64())),
+        (Number::Integer(n), Number::Integer(m)) if small(*n, *m).is_some() => Ok(Number::Integer(small(*n, *m).unwrap())),
+        _ => Ok(Number::from_big(big(&a.to_big(), &b.to_big()))),
+    }
+}
+
+/// Apply an operation which is only defined for integers, raising a type error if either argument is a float
+fn integer_only(
+    a: Number,
+    b: Number,
+    small: fn(i64, i64) -> Option<i64>,
+    big: fn(&BigInt, &BigInt) -> Result<BigInt, Term>,
+) -> Evaluation {
+    require_integer(&a)?;
+    require_integer(&b)?;
+    match (&a, &b) {
+        (Number::Integer(n), Number::Integer(m)) if small(*n, *m).is_some() => Ok(Number::Integer(small(*n, *m).unwrap())),
+        _ => Ok(Number::from_big(big(&a.to_big(), &b.to_big())?)),
+    }
+}
+
+fn float_function(num: Number, f: fn(f64) -> f64) -> Evaluation {
+    float_result(f(num.to_f64()))
+}
+
+fn rounding_function(num: Number, f: fn(f64) -> f64) -> Evaluation {
+    match num {
+        Number::Float(n) => BigInt::from_f64(f(n)).map(Number::from_big).ok_or_else(|| evaluation_error("undefined")),
+        num => Ok(num),
+    }
+}
+
+fn negate(num: Number) -> Evaluation {
+    match num {
+        Number::Integer(n) if n.checked_neg().is_some() => Ok(Number::Integer(-n)),
+        Number::Float(n) => Ok(Number::Float(-n)),
+        num => Ok(Number::from_big(num.to_big().neg())),
+    }
+}
+
+fn absolute(num: Number) -> Evaluation {
+    match num {
+        Number::Integer(n) if n.checked_abs().is_some() => Ok(Number::Integer(n.abs())),
+        Number::Float(n) => Ok(Number::Float(n.abs())),
+        num => Ok(Number::from_big(num.to_big().abs())),
+    }
+}
+
+fn sign(num: Number) -> Evaluation {
+    match num {
+        Number::Integer(n) => Ok(Number::Integer(n.signum())),
+        Number::BigInteger(n) => Ok(Number::Integer(if n.is_negative() { -1 } else { 1 })),
+        Number::Float(n) => Ok(Number::Float(if n == 0.0 { 0.0 } else { n.signum() })),
+    }
+}
+
+fn bitwise_not(num: Number) -> Evaluation {
+    require_integer(&num)?;
+    match num {
+        Number::Integer(n) => Ok(Number::Integer(!n)),
+        num => Ok(Number::from_big(num.to_big().neg().sub(&BigInt::from_i64(1)))),
+    }
+}
+
+fn most_significant_bit(num: Number) -> Evaluation {
+    require_integer(&num)?;
+    match num {
+        Number::Integer(n) if n > 0 => Ok(Number::Integer(63 - n.leading_zeros() as i64)),
+        Number::BigInteger(n) if !n.is_negative() => Ok(Number::Integer(n.bit_length() as i64 - 1)),
+        num => Err(type_error("not_less_than_one", num.into_term())),
+    }
+}
+
+fn logarithm(num: Number) -> Evaluation {
+    match num.to_f64() {
+        n if n <= 0.0 => Err(evaluation_error("undefined")),
+        n => float_result(n.ln()),
+    }
+}
+
+/// Divide two numbers, giving an integer only if both are integers and there's no remainder
+fn divide(a: Number, b: Number) -> Evaluation {
+    match (&a, &b) {
+        _ if b.to_f64() == 0.0 => Err(evaluation_error("zero_divisor")),
+        (Number::Integer(n), Number::Integer(m)) if n.checked_rem(*m) == Some(0) => Ok(Number::Integer(n / m)),
+        _ if !a.is_float() && !b.is_float() => {
+            match divide_big(&a.to_big(), &b.to_big())? {
+                (quotient, remainder) if remainder.is_zero() => Ok(Number::from_big(quotient)),
+                _ => float_result(a.to_f64() / b.to_f64()),
+            }
+        },
+        _ => float_result(a.to_f64() / b.to_f64()),
+    }
+}
+
+fn divide_big(a: &BigInt, b: &BigInt) -> Result<(BigInt, BigInt), Term> {
+    a.div_rem(b).ok_or_else(|| evaluation_error("zero_divisor"))
+}
+
+/// The remainder of floored division, which has the same sign as the divisor
+fn modulo_small(n: i64, m: i64) -> Option<i64> {
+    let remainder = n.checked_rem(m)?;
+    match remainder != 0 && (remainder < 0) != (m < 0) {
+        true => Some(remainder + m),
+        false => Some(remainder),
+    }
+}
+
+fn modulo_big(n: &BigInt, m: &BigInt) -> Result<BigInt, Term> {
+    let (_, remainder) = divide_big(n, m)?;
+    match !remainder.is_zero() && remainder.is_negative() != m.is_negative() {
+        true => Ok(remainder.add(m)),
+        false => Ok(remainder),
+    }
+}
+
+/// Division which rounds toward negative infinity
+fn floor_divide_small(n: i64, m: i64) -> Option<i64> {
+    let quotient = n.checked_div(m)?;
+    match n % m != 0 && (n < 0) != (m < 0) {
+        true => Some(quotient - 1),
+        false => Some(quotient),
+    }
+}
+
+fn floor_divide_big(n: &BigInt, m: &BigInt) -> Result<BigInt, Term> {
+    let (quotient, remainder) = divide_big(n, m)?;
+    match !remainder.is_zero() && n.is_negative() != m.is_negative() {
+        true => Ok(quotient.sub(&BigInt::from_i64(1))),
+        false => Ok(quotient),
+    }
+}
+
+/// Raise a number to a power, giving an integer if both are integers, in which case the exponent can only be
+/// negative if the base is 1 or -1
+fn power(base: Number, exponent: Number) -> Evaluation {
+    if base.is_float() || exponent.is_float() {
+        return float_result(base.to_f64().powf(exponent.to_f64()));
+    }
+
+    let (base, exponent) = (base.to_big(), exponent.to_big());
+    let one = BigInt::from_i64(1);
+    if exponent.is_negative() {
+        return match base.abs() == one {
+            true if base.is_negative() && exponent.bitwise(&one, |x, y| x & y).is_zero() => Ok(Number::Integer(1)),
+            true => Ok(Number::from_big(base)),
+            false if base.is_zero() => Err(evaluation_error("zero_divisor")),
+            false => Err(type_error("float", Number::from_big(base).into_term())),
+        };
+    }
+
+    match exponent.to_i64().and_then(|n| u32::try_from(n).ok()) {
+        Some(exponent) => Ok(Number::from_big(base.pow(exponent))),
+        // Only 0, 1, and -1 can be raised to such a large power without running out of memory
+        None if base.is_zero() => Ok(Number::Integer(0)),
+        None if base.abs() == one => power(Number::from_big(base), Number::from_big(exponent.bitwise(&one, |x, y| x & y))),
+        None => Err(resource_error("memory")),
+    }
+}
+
+fn shift(a: Number, b: Number, left: bool) -> Evaluation {
+    require_integer(&a)?;
+    require_integer(&b)?;
+
+    // Shifting by a negative amount shifts in the opposite direction
+    let (left, amount) = match b.to_big().to_i64() {
+        Some(n) if n < 0 => (!left, n.unsigned_abs()),
+        Some(n) => (left, n as u64),
+        None if b.to_big().is_negative() == left => return Ok(Number::Integer(if a.to_big().is_negative() { -1 } else { 0 })),
+        None => return Err(resource_error("memory")),
+    };
+
+    match (a, left) {
+        (Number::Integer(n), true) if amount < 63 && (n << amount) >> amount == n => Ok(Number::Integer(n << amount)),
+        (Number::Integer(n), false) => Ok(Number::Integer(n >> amount.min(63))),
+        (a, true) => match u32::try_from(amount) {
+            Ok(amount) => Ok(Number::from_big(a.to_big().shift_left(amount))),
+            Err(_) => Err(resource_error("memory")),
+        },
+        (a, false) => Ok(Number::from_big(a.to_big().floor_shift_right(amount.min(u32::MAX as u64) as u32))),
+    }
+}
+
+fn gcd_small(n: i64, m: i64) -> Option<i64> {
+    let (mut a, mut b) = (n.checked_abs()?, m.checked_abs()?);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    Some(a)
+}
+
+fn gcd_big(a: &BigInt, b: &BigInt) -> BigInt {
+    let (mut a, mut b) = (a.abs(), b.abs());
+    while !b.is_zero() {
+        let (_, remainder) = a.div_rem(&b).unwrap();
+        a = b;
+        b = remainder;
+    }
+    a
+}
@@ -0,0 +1,105 @@
+
+use std::fmt;
+use std::ptr;
+use std::ops::Deref;
+use std::cmp::Ordering;
+use std::hash::{ Hash, Hasher };
+use std::sync::{ Mutex, OnceLock };
+use std::collections::HashSet;
+
+
+/// An interned name, used for atoms, functors, and variable names.  Each distinct name is stored only once, for the
+/// life of the program, so atoms can be copied freely and compared by pointer
+#[derive(Copy, Clone)]
+pub struct Atom(&'static str);
+
+/// Atoms which the solver and builtins use on every call.  These are in the atom table from the start, so they can be
+/// used without locking the table and looking up their names
+pub static TRUE: Atom = Atom("true");
+pub static FAIL: Atom = Atom("fail");
+pub static CUT: Atom = Atom("!");
+pub static SOFT_CUT: Atom = Atom("$soft_cut");
+pub static EXIT_CATCH: Atom = Atom("$exit_catch");
+
+fn table() -> &'static Mutex<HashSet<&'static str>> {
+    static TABLE: OnceLock<Mutex<HashSet<&'static str>>> = OnceLock::new();
+    TABLE.get_or_init(|| Mutex::new([TRUE, FAIL, CUT, SOFT_CUT, EXIT_CATCH].iter().map(Atom::as_str).collect()))
+}
+
+impl Atom {
+    /// Returns the atom with the given name, adding it to the atom table if it's not already there
+    pub fn new(name: &str) -> Atom {
+        let mut table = table().lock().unwrap();
+        match table.get(name) {
+            Some(interned) => Atom(interned),
+            None => {
+                let interned: &'static str = Box::leak(name.to_string().into_boxed_str());
+                table.insert(interned);
+                Atom(interned)
+            },
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl Deref for Atom {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.0
+    }
+}
+
+impl PartialEq for Atom {
+    fn eq(&self, other: &Atom) -> bool {
+        ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for Atom { }
+
+impl PartialEq<str> for Atom {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Atom {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl Hash for Atom {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_ptr().hash(state);
+    }
+}
+
+impl PartialOrd for Atom {
+    fn partial_cmp(&self, other: &Atom) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Atom {
+    /// Atoms are ordered alphabetically, rather than by where they're stored
+    fn cmp(&self, other: &Atom) -> Ordering {
+        self.0.cmp(other.0)
+    }
+}
+
+impl fmt::Display for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Debug for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
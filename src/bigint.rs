@@ -0,0 +1,391 @@
+
+use std::fmt;
+use std::cmp::Ordering;
+
+
+/// An arbitrary precision integer, stored as a sign and a magnitude made of 32-bit digits with the least significant
+/// digit first.  The magnitude never has leading zero digits, and zero is always positive, so that two equal numbers
+/// always have the same representation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BigInt {
+    negative: bool,
+    digits: Vec<u32>,
+}
+
+const DIGIT_BITS: u32 = 32;
+/// The largest power of ten that fits in a digit, used to convert to and from decimal strings
+const DECIMAL_BASE: u32 = 1_000_000_000;
+const DECIMAL_BASE_DIGITS: usize = 9;
+
+impl BigInt {
+    pub fn zero() -> Self {
+        BigInt { negative: false, digits: vec!() }
+    }
+
+    fn from_parts(negative: bool, mut digits: Vec<u32>) -> Self {
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        let negative = negative && !digits.is_empty();
+        BigInt { negative, digits }
+    }
+
+    pub fn from_i64(num: i64) -> Self {
+        let magnitude = num.unsigned_abs();
+        BigInt::from_parts(num < 0, vec!(magnitude as u32, (magnitude >> DIGIT_BITS) as u32))
+    }
+
+    /// Convert a float to an integer, discarding any fractional part, or return None if it's infinite or NaN
+    pub fn from_f64(num: f64) -> Option<Self> {
+        if !num.is_finite() {
+            return None;
+        }
+
+        let num = num.trunc();
+        if num.abs() < (1u64 << 63) as f64 {
+            return Some(BigInt::from_i64(num as i64));
+        }
+
+        // Large floats are always whole numbers, so the mantissa only needs to be shifted left by the exponent
+        let bits = num.to_bits();
+        let exponent = ((bits >> 52) & 0x7ff) as u32 - 1075;
+        let mantissa = (bits & ((1 << 52) - 1)) | (1 << 52);
+        let magnitude = BigInt::from_i64(mantissa as i64).shift_left(exponent);
+        Some(if num < 0.0 { magnitude.neg() } else { magnitude })
+    }
+
+    /// Parse a string of decimal digits with an optional leading minus sign
+    pub fn parse(text: &str) -> Option<Self> {
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+
+        if text.is_empty() || !text.chars().all(|ch| ch.is_ascii_digit()) {
+            return None;
+        }
+
+        let mut digits = vec!();
+        let first = text.len() % DECIMAL_BASE_DIGITS;
+        let chunks = std::iter::once(&text[..first])
+            .chain((first..text.len()).step_by(DECIMAL_BASE_DIGITS).map(|i| &text[i..i + DECIMAL_BASE_DIGITS]));
+        for chunk in chunks.filter(|chunk| !chunk.is_empty()) {
+            let scale = 10u32.pow(chunk.len() as u32);
+            multiply_add_small(&mut digits, scale, chunk.parse().unwrap());
+        }
+        Some(BigInt::from_parts(negative, digits))
+    }
+
+    /// Returns the number as an i64, if it's small enough to fit
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.digits.len() > 2 {
+            return None;
+        }
+
+        let magnitude = self.digits.iter().rev().fold(0u64, |acc, digit| (acc << DIGIT_BITS) | *digit as u64);
+        match self.negative {
+            true if magnitude <= 1 << 63 => Some((magnitude as i64).wrapping_neg()),
+            false if magnitude < 1 << 63 => Some(magnitude as i64),
+            _ => None,
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let magnitude = self.digits.iter().rev().fold(0.0, |acc, digit| acc * 4294967296.0 + *digit as f64);
+        if self.negative { -magnitude } else { magnitude }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn neg(&self) -> Self {
+        BigInt::from_parts(!self.negative, self.digits.clone())
+    }
+
+    pub fn abs(&self) -> Self {
+        BigInt::from_parts(false, self.digits.clone())
+    }
+
+    pub fn add(&self, other: &BigInt) -> Self {
+        if self.negative == other.negative {
+            return BigInt::from_parts(self.negative, add_magnitudes(&self.digits, &other.digits));
+        }
+
+        // The signs differ, so subtract the smaller magnitude from the larger and take the sign of the larger
+        match compare_magnitudes(&self.digits, &other.digits) {
+            Ordering::Less => BigInt::from_parts(other.negative, subtract_magnitudes(&other.digits, &self.digits)),
+            _ => BigInt::from_parts(self.negative, subtract_magnitudes(&self.digits, &other.digits)),
+        }
+    }
+
+    pub fn sub(&self, other: &BigInt) -> Self {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &BigInt) -> Self {
+        let mut result = vec![0u32; self.digits.len() + other.digits.len()];
+        for (i, a) in self.digits.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, b) in other.digits.iter().enumerate() {
+                let total = result[i + j] as u64 + (*a as u64 * *b as u64) + carry;
+                result[i + j] = total as u32;
+                carry = total >> DIGIT_BITS;
+            }
+            result[i + other.digits.len()] = carry as u32;
+        }
+        BigInt::from_parts(self.negative != other.negative, result)
+    }
+
+    /// Divide by another number, returning the quotient rounded toward zero and the remainder (which has the same
+    /// sign as the dividend), or None if dividing by zero
+    pub fn div_rem(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+
+        let (quotient, remainder) = if other.digits.len() == 1 {
+            let mut quotient = self.digits.clone();
+            let remainder = divide_small(&mut quotient, other.digits[0]);
+            (quotient, vec!(remainder))
+        } else {
+            divide_magnitudes(&self.digits, &other.digits)
+        };
+
+        Some((
+            BigInt::from_parts(self.negative != other.negative, quotient),
+            BigInt::from_parts(self.negative, remainder),
+        ))
+    }
+
+    pub fn pow(&self, mut exponent: u32) -> Self {
+        let mut result = BigInt::from_i64(1);
+        let mut base = self.clone();
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = result.mul(&base);
+            }
+            exponent >>= 1;
+            if exponent > 0 {
+                base = base.mul(&base);
+            }
+        }
+        result
+    }
+
+    /// Multiply the magnitude by 2 to the power of `bits`, keeping the sign
+    pub fn shift_left(&self, bits: u32) -> Self {
+        let (whole, part) = ((bits / DIGIT_BITS) as usize, bits % DIGIT_BITS);
+        let mut digits = vec![0u32; whole];
+        let mut carry = 0u32;
+        for digit in self.digits.iter() {
+            digits.push((digit << part) | carry);
+            carry = if part == 0 { 0 } else { digit >> (DIGIT_BITS - part) };
+        }
+        digits.push(carry);
+        BigInt::from_parts(self.negative, digits)
+    }
+
+    /// Divide the magnitude by 2 to the power of `bits`, rounding toward zero and keeping the sign
+    pub fn shift_right(&self, bits: u32) -> Self {
+        let (whole, part) = ((bits / DIGIT_BITS) as usize, bits % DIGIT_BITS);
+        if whole >= self.digits.len() {
+            return BigInt::zero();
+        }
+
+        let digits = &self.digits[whole..];
+        let shifted = (0..digits.len()).map(|i| {
+            let high = if part == 0 { 0 } else { digits.get(i + 1).map(|d| d << (DIGIT_BITS - part)).unwrap_or(0) };
+            (digits[i] >> part) | high
+        }).collect();
+        BigInt::from_parts(self.negative, shifted)
+    }
+
+    /// Divide by 2 to the power of `bits`, rounding toward negative infinity like an arithmetic shift right
+    pub fn floor_shift_right(&self, bits: u32) -> Self {
+        let quotient = self.shift_right(bits);
+        if self.negative && quotient.shift_left(bits) != *self {
+            quotient.sub(&BigInt::from_i64(1))
+        } else {
+            quotient
+        }
+    }
+
+    /// Apply a bitwise operation to each digit of two numbers, treating negative numbers as if they were in two's
+    /// complement form with an infinite number of leading one bits
+    pub fn bitwise(&self, other: &BigInt, op: fn(u32, u32) -> u32) -> Self {
+        // One extra digit is enough to hold the sign of either number
+        let len = self.digits.len().max(other.digits.len()) + 1;
+        let a = self.to_twos_complement(len);
+        let b = other.to_twos_complement(len);
+        let result: Vec<u32> = a.iter().zip(b.iter()).map(|(a, b)| op(*a, *b)).collect();
+
+        match result[len - 1] >> (DIGIT_BITS - 1) {
+            0 => BigInt::from_parts(false, result),
+            _ => BigInt::from_parts(true, negate_digits(&result)),
+        }
+    }
+
+    fn to_twos_complement(&self, len: usize) -> Vec<u32> {
+        let mut digits = self.digits.clone();
+        digits.resize(len, 0);
+        match self.negative {
+            true => negate_digits(&digits),
+            false => digits,
+        }
+    }
+
+    /// Returns the number of bits needed to represent the magnitude
+    pub fn bit_length(&self) -> u64 {
+        match self.digits.last() {
+            Some(last) => (self.digits.len() as u64 - 1) * DIGIT_BITS as u64 + (DIGIT_BITS - last.leading_zeros()) as u64,
+            None => 0,
+        }
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare_magnitudes(&self.digits, &other.digits),
+            (true, true) => compare_magnitudes(&other.digits, &self.digits),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+
+        // Convert to base 10^9 so that each chunk can be printed as a group of decimal digits
+        let mut magnitude = self.digits.clone();
+        let mut chunks = vec!();
+        while !magnitude.is_empty() {
+            chunks.push(divide_small(&mut magnitude, DECIMAL_BASE));
+            while magnitude.last() == Some(&0) {
+                magnitude.pop();
+            }
+        }
+
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", chunks.pop().unwrap())?;
+        for chunk in chunks.iter().rev() {
+            write!(f, "{:0width$}", chunk, width = DECIMAL_BASE_DIGITS)?;
+        }
+        Ok(())
+    }
+}
+
+/// Invert each digit and add one, which negates a number in two's complement form
+fn negate_digits(digits: &[u32]) -> Vec<u32> {
+    let mut carry = 1u64;
+    digits.iter().map(|digit| {
+        let total = !*digit as u64 + carry;
+        carry = total >> DIGIT_BITS;
+        total as u32
+    }).collect()
+}
+
+fn compare_magnitudes(a: &[u32], b: &[u32]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut result = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0u64;
+    for (i, digit) in long.iter().enumerate() {
+        let total = *digit as u64 + short.get(i).copied().unwrap_or(0) as u64 + carry;
+        result.push(total as u32);
+        carry = total >> DIGIT_BITS;
+    }
+    result.push(carry as u32);
+    result
+}
+
+/// Subtract the magnitude `b` from `a`, which must be greater than or equal to `b`
+fn subtract_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, digit) in a.iter().enumerate() {
+        let mut total = *digit as i64 - b.get(i).copied().unwrap_or(0) as i64 - borrow;
+        borrow = 0;
+        if total < 0 {
+            total += 1 << DIGIT_BITS;
+            borrow = 1;
+        }
+        result.push(total as u32);
+    }
+    result
+}
+
+fn multiply_add_small(digits: &mut Vec<u32>, factor: u32, addend: u32) {
+    let mut carry = addend as u64;
+    for digit in digits.iter_mut() {
+        let total = *digit as u64 * factor as u64 + carry;
+        *digit = total as u32;
+        carry = total >> DIGIT_BITS;
+    }
+    if carry > 0 {
+        digits.push(carry as u32);
+    }
+}
+
+/// Divide the magnitude in place by a single digit, returning the remainder
+fn divide_small(digits: &mut [u32], divisor: u32) -> u32 {
+    let mut remainder = 0u64;
+    for digit in digits.iter_mut().rev() {
+        let current = (remainder << DIGIT_BITS) | *digit as u64;
+        *digit = (current / divisor as u64) as u32;
+        remainder = current % divisor as u64;
+    }
+    remainder as u32
+}
+
+/// Divide two magnitudes using binary long division, one bit of the dividend at a time
+fn divide_magnitudes(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if compare_magnitudes(a, b) == Ordering::Less {
+        return (vec!(), a.to_vec());
+    }
+
+    let mut quotient = vec![0u32; a.len()];
+    let mut remainder: Vec<u32> = vec!();
+    for i in (0..a.len() * DIGIT_BITS as usize).rev() {
+        // Shift the next bit of the dividend into the remainder
+        let bit = (a[i / DIGIT_BITS as usize] >> (i % DIGIT_BITS as usize)) & 1;
+        let mut carry = bit;
+        for digit in remainder.iter_mut() {
+            let next = *digit >> (DIGIT_BITS - 1);
+            *digit = (*digit << 1) | carry;
+            carry = next;
+        }
+        if carry > 0 {
+            remainder.push(carry);
+        }
+
+        if compare_magnitudes(&remainder, b) != Ordering::Less {
+            remainder = subtract_magnitudes(&remainder, b);
+            while remainder.last() == Some(&0) {
+                remainder.pop();
+            }
+            quotient[i / DIGIT_BITS as usize] |= 1 << (i % DIGIT_BITS as usize);
+        }
+    }
+    (quotient, remainder)
+}
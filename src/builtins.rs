@@ -1,197 +1,562 @@
 
-use crate::tree::{ Term, TermKind, atom, integer };
-use crate::solver::{ Database, Query, Partial, Bindings, simplify_term, unify_term, compare_term };
-
-pub type BuiltinPredicate = fn(&Database, &Term, &Bindings, usize) -> Option<Partial>;
+use std::rc::Rc;
+use std::cmp::Ordering;
+use std::iter;
+use std::sync::OnceLock;
+use std::collections::HashMap;
+
+use crate::arithmetic;
+use crate::errors::{ error, instantiation_error, type_error, domain_error };
+use crate::tree::Expr;
+use crate::atoms::{ Atom, TRUE, FAIL };
+use crate::flags::Flags;
+use crate::tree::{ Term, TermKind, atom, compound, conjunct, disjunct, if_then_else };
+use crate::solver::{ Machine, unify_term, unifiable };
+use crate::writer::format_listing;
+use crate::parser::define_operators;
+
+pub type BuiltinPredicate = fn(&mut Machine, &Term) -> Option<Term>;
+
+/// The builtin predicates, by name and arity.  `call/N` isn't included, since it accepts any number of arguments
+static BUILTINS: &[(&str, usize, BuiltinPredicate)] = &[
+    ("true", 0, builtin_true_0),
+    ("!", 0, builtin_cut_0),
+    ("$soft_cut", 0, builtin_soft_cut_0),
+    ("fail", 0, builtin_fail_0),
+    ("\\+", 1, builtin_not_1),
+    ("not", 1, builtin_not_1),
+    ("nl", 0, builtin_nl_0),
+    ("write", 1, builtin_write_1),
+    ("op", 3, builtin_op_3),
+    ("catch", 3, builtin_catch_3),
+    ("throw", 1, builtin_throw_1),
+    ("$unify", 2, builtin_unify_2),
+    ("assert", 1, builtin_assertz_1),
+    ("asserta", 1, builtin_asserta_1),
+    ("assertz", 1, builtin_assertz_1),
+    ("retract", 1, builtin_retract_1),
+    ("$retract", 3, builtin_retract_3),
+    ("retractall", 1, builtin_retractall_1),
+    ("abolish", 1, builtin_abolish_1),
+    ("dynamic", 1, builtin_dynamic_1),
+    ("consult", 1, builtin_consult_1),
+    ("make", 0, builtin_make_0),
+    ("listing", 0, builtin_listing_0),
+    ("listing", 1, builtin_listing_1),
+    ("jiti_list", 0, builtin_jiti_list_0),
+    ("jiti_list", 1, builtin_jiti_list_1),
+    ("set_prolog_flag", 2, builtin_set_prolog_flag_2),
+    ("current_prolog_flag", 2, builtin_current_prolog_flag_2),
+    ("$exit_catch", 0, builtin_exit_catch_0),
+    ("is", 2, builtin_is_2),
+    ("=", 2, builtin_equal_2),
+    ("\\=", 2, builtin_not_equal_2),
+    ("<", 2, builtin_less_than_2),
+    (">", 2, builtin_greater_than_2),
+    ("=<", 2, builtin_less_than_or_equal_2),
+    ("<=", 2, builtin_less_than_or_equal_2),
+    (">=", 2, builtin_greater_than_or_equal_2),
+    ("=:=", 2, builtin_arithmetic_equal_2),
+    ("=\\=", 2, builtin_arithmetic_not_equal_2),
+];
+
+fn builtin_table() -> &'static HashMap<(Atom, usize), BuiltinPredicate> {
+    static TABLE: OnceLock<HashMap<(Atom, usize), BuiltinPredicate>> = OnceLock::new();
+    TABLE.get_or_init(|| BUILTINS.iter().map(|(name, arity, func)| ((Atom::new(name), *arity), *func)).collect())
+}
 
 pub fn lookup_builtin(term: &Term) -> Option<BuiltinPredicate> {
-    let name = match &**term {
-        TermKind::Atom(s) => s,
-        TermKind::Compound(s, _) => s,
+    let (name, arity) = match &**term {
+        TermKind::Atom(name) => (*name, 0),
+        TermKind::Compound(name, args) => (*name, args.len()),
+        // A list of files as a goal, like `[file1, file2]`, consults each of them
+        TermKind::List(_, _) => return Some(builtin_consult_list),
         _ => return None,
     };
 
-    let builtin: Option<BuiltinPredicate> = match name.as_str() {
-        "call"      => Some(builtin_call),
-        _           => None,
-    };
+    if name == "call" {
+        return Some(builtin_call);
+    }
+    builtin_table().get(&(name, arity)).copied()
+}
+
+/// Returns the name and arity of each builtin predicate, except for the hidden ones used internally, whose names
+/// start with `$`
+pub fn builtin_indicators() -> impl Iterator<Item = (&'static str, usize)> {
+    BUILTINS.iter()
+        .map(|(name, arity, _)| (*name, *arity))
+        .filter(|(name, _)| !name.starts_with('$'))
+        .chain(iter::once(("call", 1)))
+}
+
+fn builtin_true_0(_machine: &mut Machine, _term: &Term) -> Option<Term> {
+    Some(TRUE.into())
+}
 
-    if builtin.is_some() {
-        return builtin;
+fn builtin_cut_0(machine: &mut Machine, _term: &Term) -> Option<Term> {
+    machine.cut();
+    Some(TRUE.into())
+}
+
+fn builtin_soft_cut_0(machine: &mut Machine, _term: &Term) -> Option<Term> {
+    machine.soft_cut();
+    Some(TRUE.into())
+}
+
+fn builtin_fail_0(_machine: &mut Machine, _term: &Term) -> Option<Term> {
+    None
+}
+
+fn builtin_not_1(machine: &mut Machine, term: &Term) -> Option<Term> {
+    let args = term.get_args()?;
+
+    // Negation as failure is equivalent to `(Goal -> fail ; true)`
+    machine.push_goal(if_then_else(args[0].clone(), Term::from(FAIL), Some(Term::from(TRUE).into())));
+    Some(TRUE.into())
+}
+
+fn builtin_catch_3(machine: &mut Machine, term: &Term) -> Option<Term> {
+    let args = term.get_args()?;
+
+    machine.push_catch(args[0].clone(), args[1].clone(), args[2].clone());
+    Some(TRUE.into())
+}
+
+fn builtin_throw_1(machine: &mut Machine, term: &Term) -> Option<Term> {
+    let args = term.get_args()?;
+
+    match &*args[0] {
+        TermKind::Var(_) => machine.throw(error(instantiation_error(), "throw", 1)),
+        _ => machine.throw(args[0].clone()),
     }
+    None
+}
 
-    let name_with_arity = match &**term {
-        TermKind::Atom(s) => format!("{}/0", s),
-        TermKind::Compound(s, args) => format!("{}/{}", s, args.len()),
-        _ => return None,
+fn builtin_exit_catch_0(machine: &mut Machine, _term: &Term) -> Option<Term> {
+    machine.exit_catch();
+    Some(TRUE.into())
+}
+
+fn builtin_unify_2(machine: &mut Machine, term: &Term) -> Option<Term> {
+    let args = term.get_args()?;
+
+    match unify_term(&args[0], &args[1], &mut machine.bindings) {
+        true => Some(TRUE.into()),
+        false => None,
+    }
+}
+
+/// Throw the error from a builtin that returned one, with the builtin's indicator as the context
+fn check_result(machine: &mut Machine, result: Result<(), Term>, name: &str, arity: usize) -> Option<Term> {
+    match result {
+        Ok(()) => Some(TRUE.into()),
+        Err(formal) => {
+            machine.throw(error(formal, name, arity));
+            None
+        },
+    }
+}
+
+/// Split a clause term into its head and body, where a fact has a body of `true`
+fn clause_parts(clause: &Term) -> Result<(Term, Term), Term> {
+    let (head, body) = match &**clause {
+        TermKind::Compound(name, args) if name == ":-" && args.len() == 2 => (args[0].clone(), args[1].clone()),
+        _ => (clause.clone(), TRUE.into()),
     };
 
-    match name_with_arity.as_str() {
-        "!/0"       => Some(builtin_cut_0),
-        "fail/0"    => Some(builtin_fail_0),
-        "nl/0"      => Some(builtin_nl_0),
-        "write/1"   => Some(builtin_write_1),
-        "is/2"      => Some(builtin_is_2),
-        "=/2"       => Some(builtin_equal_2),
-        "\\=/2"     => Some(builtin_not_equal_2),
-        "</2"       => Some(builtin_less_than_2),
-        ">/2"       => Some(builtin_greater_than_2),
-        "<=/2"      => Some(builtin_less_than_or_equal_2),
-        ">=/2"      => Some(builtin_greater_than_or_equal_2),
-        "+/2"       => Some(builtin_add_2),
-        "-/2"       => Some(builtin_subtract_2),
-        "*/2"       => Some(builtin_multiply_2),
-        "//2"       => Some(builtin_divide_2),
-        _ => None,
+    match &*head {
+        TermKind::Var(_) => Err(instantiation_error()),
+        TermKind::Atom(_) | TermKind::Compound(_, _) => Ok((head, body)),
+        _ => Err(type_error("callable", head)),
     }
 }
 
-fn builtin_cut_0(_db: &Database, _term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
-    Some(Partial::new(atom("true"), bindings.clone(), at_rule))
+/// Parse a predicate indicator like `foo/2` into its name and arity
+fn predicate_indicator(term: &Term) -> Result<(Atom, usize), Term> {
+    let args = match &**term {
+        TermKind::Var(_) => return Err(instantiation_error()),
+        TermKind::Compound(name, args) if name == "/" && args.len() == 2 => args,
+        _ => return Err(type_error("predicate_indicator", term.clone())),
+    };
+
+    match (&*args[0], &*args[1]) {
+        (TermKind::Var(_), _) | (_, TermKind::Var(_)) => Err(instantiation_error()),
+        (TermKind::Atom(name), TermKind::Integer(arity)) if *arity >= 0 => Ok((*name, *arity as usize)),
+        (TermKind::Atom(_), TermKind::Integer(_)) => Err(domain_error("not_less_than_zero", args[1].clone())),
+        (TermKind::Atom(_), _) => Err(type_error("integer", args[1].clone())),
+        _ => Err(type_error("atom", args[0].clone())),
+    }
 }
 
-fn builtin_fail_0(_db: &Database, _term: &Term, _bindings: &Bindings, _at_rule: usize) -> Option<Partial> {
-    None
+fn assert_clause(machine: &mut Machine, clause: &Term, at_start: bool) -> Result<(), Term> {
+    let (head, body) = clause_parts(clause)?;
+    let body: Option<Expr> = match &*body {
+        TermKind::Atom(name) if name == "true" => None,
+        TermKind::Integer(_) | TermKind::BigInteger(_) | TermKind::Float(_) | TermKind::String(_) => {
+            return Err(type_error("callable", body));
+        },
+        _ => Some(body.into()),
+    };
+
+    machine.db.add_clause(head, body, at_start)
 }
 
-fn builtin_nl_0(_db: &Database, _term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
-    println!("");
-    Some(Partial::new(atom("true"), bindings.clone(), at_rule))
+fn builtin_asserta_1(machine: &mut Machine, term: &Term) -> Option<Term> {
+    let args = term.get_args()?;
+
+    let result = assert_clause(machine, &args[0], true);
+    check_result(machine, result, "asserta", 1)
 }
 
-fn builtin_write_1(_db: &Database, term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
+fn builtin_assertz_1(machine: &mut Machine, term: &Term) -> Option<Term> {
     let args = term.get_args()?;
 
-    print!("{}", args[0]);
-    Some(Partial::new(atom("true"), bindings.clone(), at_rule))
+    let result = assert_clause(machine, &args[0], false);
+    check_result(machine, result, "assertz", 1)
 }
 
-fn builtin_is_2(db: &Database, term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
+fn builtin_retract_1(machine: &mut Machine, term: &Term) -> Option<Term> {
     let args = term.get_args()?;
 
-    let rhs = simplify_term(db, &args[1], bindings, at_rule).unwrap();
-    println!("{:?} {:?}", args[0], rhs.result);
-    match unify_term(&args[0], &rhs.result) {
-        Some((result, newbindings)) => {
-            let bindings = newbindings.merge(bindings)?;
-            Some(Partial::new(result, bindings, at_rule))
+    let result = clause_parts(&args[0]).and_then(|(head, body)| {
+        let (name, arity) = head.indicator().ok_or_else(|| type_error("callable", head.clone()))?;
+        machine.db.check_modify(name, arity)?;
+        Ok((head, body))
+    });
+
+    match result {
+        Ok((head, body)) => {
+            let generation = machine.db.generation();
+            match machine.retract(&head, &body, generation) {
+                true => Some(TRUE.into()),
+                false => None,
+            }
         },
-        None => None,
+        Err(formal) => check_result(machine, Err(formal), "retract", 1),
     }
 }
 
-fn builtin_equal_2(_db: &Database, term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
+/// Resumes a call to retract/1 when backtracking, only considering clauses that existed when it was first called
+fn builtin_retract_3(machine: &mut Machine, term: &Term) -> Option<Term> {
     let args = term.get_args()?;
 
-    println!("Comparing {} with {}", &args[0], &args[1]);
-    if compare_term(&args[0], &args[1]) {
-        Some(Partial::new(atom("true"), bindings.clone(), at_rule))
-    } else {
-        None
+    let generation = match &*args[2] {
+        TermKind::Integer(generation) => *generation as u64,
+        _ => return None,
+    };
+
+    match machine.retract(&args[0], &args[1], generation) {
+        true => Some(TRUE.into()),
+        false => None,
     }
 }
 
-fn builtin_not_equal_2(_db: &Database, term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
+fn builtin_retractall_1(machine: &mut Machine, term: &Term) -> Option<Term> {
     let args = term.get_args()?;
 
-    println!("Comparing {} with {}", &args[0], &args[1]);
-    if !compare_term(&args[0], &args[1]) {
-        Some(Partial::new(atom("true"), bindings.clone(), at_rule))
-    } else {
-        None
-    }
+    let result = clause_parts(&args[0]).and_then(|(head, _)| {
+        let (name, arity) = head.indicator().ok_or_else(|| type_error("callable", head.clone()))?;
+        machine.db.check_modify(name, arity)?;
+        if !machine.db.is_defined(name, arity) {
+            machine.db.declare_dynamic(name, arity)?;
+        }
+        machine.retract_all(&head);
+        Ok(())
+    });
+    check_result(machine, result, "retractall", 1)
 }
 
-fn builtin_less_than_2(_db: &Database, term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
+fn builtin_abolish_1(machine: &mut Machine, term: &Term) -> Option<Term> {
     let args = term.get_args()?;
 
-    println!("Comparing {} with {}", &args[0], &args[1]);
-    match (&*args[0], &*args[1]) {
-        (TermKind::Integer(n), TermKind::Integer(m)) if n < m => Some(Partial::new(atom("true"), bindings.clone(), at_rule)),
-        _ => None
-    }
+    let result = predicate_indicator(&args[0]).and_then(|(name, arity)| machine.db.abolish(name, arity));
+    check_result(machine, result, "abolish", 1)
 }
 
-fn builtin_greater_than_2(_db: &Database, term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
+/// Declare predicates as dynamic, given either a single indicator, or a list or conjunction of them
+fn builtin_dynamic_1(machine: &mut Machine, term: &Term) -> Option<Term> {
     let args = term.get_args()?;
 
-    println!("Comparing {} with {}", &args[0], &args[1]);
-    match (&*args[0], &*args[1]) {
-        (TermKind::Integer(n), TermKind::Integer(m)) if n > m => Some(Partial::new(atom("true"), bindings.clone(), at_rule)),
-        _ => None
+    let mut pending = vec!(args[0].clone());
+    let mut result = Ok(());
+    while let Some(next) = pending.pop() {
+        match &*next {
+            TermKind::List(head, tail) => { pending.push(tail.clone()); pending.push(head.clone()); },
+            TermKind::Compound(name, args) if name == "," && args.len() == 2 => {
+                pending.push(args[1].clone());
+                pending.push(args[0].clone());
+            },
+            TermKind::EmptyList => { },
+            _ => {
+                result = predicate_indicator(&next).and_then(|(name, arity)| machine.db.declare_dynamic(name, arity));
+                if result.is_err() {
+                    break;
+                }
+            },
+        }
     }
+    check_result(machine, result, "dynamic", 1)
 }
 
-fn builtin_less_than_or_equal_2(_db: &Database, term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
+/// Consult the file with the given name, or each file in a list of names
+fn consult_files(machine: &mut Machine, files: &Term) -> Result<(), Term> {
+    let mut list = files;
+    loop {
+        match &**list {
+            TermKind::List(head, tail) => {
+                consult_files(machine, head)?;
+                list = tail;
+            },
+            TermKind::EmptyList => return Ok(()),
+            TermKind::Atom(name) => return machine.db.consult(name),
+            TermKind::String(name) => return machine.db.consult(name),
+            TermKind::Var(_) => return Err(instantiation_error()),
+            _ => return Err(type_error("atom", list.clone())),
+        }
+    }
+}
+
+fn builtin_consult_1(machine: &mut Machine, term: &Term) -> Option<Term> {
     let args = term.get_args()?;
 
-    println!("Comparing {} with {}", &args[0], &args[1]);
-    match (&*args[0], &*args[1]) {
-        (TermKind::Integer(n), TermKind::Integer(m)) if n <= m => Some(Partial::new(atom("true"), bindings.clone(), at_rule)),
-        _ => None
+    let result = consult_files(machine, &args[0]);
+    check_result(machine, result, "consult", 1)
+}
+
+fn builtin_consult_list(machine: &mut Machine, term: &Term) -> Option<Term> {
+    let result = consult_files(machine, term);
+    check_result(machine, result, "consult", 1)
+}
+
+fn builtin_make_0(machine: &mut Machine, _term: &Term) -> Option<Term> {
+    let result = machine.db.make().map(|reloaded| {
+        for path in reloaded {
+            println!("% Reloaded {}", path);
+        }
+    });
+    check_result(machine, result, "make", 0)
+}
+
+/// Print the clauses of each of the given predicates in source form
+fn print_listing(machine: &Machine, indicators: &[(Atom, usize)]) {
+    print!("{}", format_listing(machine.db, indicators));
+}
+
+fn builtin_listing_0(machine: &mut Machine, _term: &Term) -> Option<Term> {
+    print_listing(machine, &machine.db.predicate_indicators());
+    Some(TRUE.into())
+}
+
+/// Print the clauses of the predicate with the given indicator, or of every predicate with the given name
+fn builtin_listing_1(machine: &mut Machine, term: &Term) -> Option<Term> {
+    let args = term.get_args()?;
+
+    let indicators = match &*args[0] {
+        TermKind::Atom(name) => machine.db.predicate_indicators().into_iter().filter(|(other, _)| other == name).collect(),
+        _ => match predicate_indicator(&args[0]) {
+            Ok(indicator) => vec!(indicator),
+            Err(formal) => return check_result(machine, Err(formal), "listing", 1),
+        },
+    };
+
+    print_listing(machine, &indicators);
+    Some(TRUE.into())
+}
+
+/// Print the indexes which have been built for each of the given predicates
+fn print_indexes(machine: &Machine, indicators: &[(Atom, usize)]) {
+    for (name, arity) in indicators {
+        let indexes = machine.db.indexes(*name, *arity)
+            .iter()
+            .map(|(argument, keys)| format!("argument {} ({} keys)", argument, keys))
+            .collect::<Vec<String>>();
+
+        match indexes.is_empty() {
+            true => println!("{}/{}: no indexes", name, arity),
+            false => println!("{}/{}: {}", name, arity, indexes.join(", ")),
+        }
     }
 }
 
-fn builtin_greater_than_or_equal_2(_db: &Database, term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
+fn builtin_jiti_list_0(machine: &mut Machine, _term: &Term) -> Option<Term> {
+    print_indexes(machine, &machine.db.predicate_indicators());
+    Some(TRUE.into())
+}
+
+/// Print the indexes of the predicate with the given indicator, or of every predicate with the given name
+fn builtin_jiti_list_1(machine: &mut Machine, term: &Term) -> Option<Term> {
     let args = term.get_args()?;
 
-    println!("Comparing {} with {}", &args[0], &args[1]);
-    match (&*args[0], &*args[1]) {
-        (TermKind::Integer(n), TermKind::Integer(m)) if n >= m => Some(Partial::new(atom("true"), bindings.clone(), at_rule)),
-        _ => None
+    let indicators = match &*args[0] {
+        TermKind::Atom(name) => machine.db.predicate_indicators().into_iter().filter(|(other, _)| other == name).collect(),
+        _ => match predicate_indicator(&args[0]) {
+            Ok(indicator) => vec!(indicator),
+            Err(formal) => return check_result(machine, Err(formal), "jiti_list", 1),
+        },
+    };
+
+    print_indexes(machine, &indicators);
+    Some(TRUE.into())
+}
+
+fn builtin_set_prolog_flag_2(machine: &mut Machine, term: &Term) -> Option<Term> {
+    let args = term.get_args()?;
+
+    let result = match (&*args[0], &*args[1]) {
+        (TermKind::Var(_), _) | (_, TermKind::Var(_)) => Err(instantiation_error()),
+        (TermKind::Atom(name), _) if machine.db.flags().get(name).is_none() => Err(domain_error("prolog_flag", args[0].clone())),
+        (TermKind::Atom(name), TermKind::Atom(value)) if machine.db.flags_mut().set(name, value) => Ok(()),
+        (TermKind::Atom(_), _) => Err(domain_error("flag_value", compound("+", vec!(args[0].clone(), args[1].clone())))),
+        _ => Err(type_error("atom", args[0].clone())),
+    };
+    check_result(machine, result, "set_prolog_flag", 2)
+}
+
+fn builtin_current_prolog_flag_2(machine: &mut Machine, term: &Term) -> Option<Term> {
+    let args = term.get_args()?;
+
+    match &*args[0] {
+        TermKind::Atom(name) => {
+            let value = machine.db.flags().get(name)?;
+            match unify_term(&args[1], &atom(&value), &mut machine.bindings) {
+                true => Some(TRUE.into()),
+                false => None,
+            }
+        },
+        TermKind::Var(_) => {
+            // Try each flag in turn as an alternative
+            let goal = Flags::NAMES.iter().rev()
+                .map(|name| conjunct(
+                    compound("$unify", vec!(args[0].clone(), atom(name))),
+                    compound("current_prolog_flag", vec!(atom(name), args[1].clone())),
+                ))
+                .reduce(|rest, goal| disjunct(goal, rest))?;
+            machine.push_goal(goal);
+            Some(TRUE.into())
+        },
+        _ => {
+            machine.throw(error(type_error("atom", args[0].clone()), "current_prolog_flag", 2));
+            None
+        },
     }
 }
 
-fn builtin_add_2(_db: &Database, term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
+fn builtin_nl_0(_machine: &mut Machine, _term: &Term) -> Option<Term> {
+    println!();
+    Some(TRUE.into())
+}
+
+fn builtin_write_1(_machine: &mut Machine, term: &Term) -> Option<Term> {
     let args = term.get_args()?;
 
-    println!("Adding {} with {}", &args[0], &args[1]);
-    match (&*args[0], &*args[1]) {
-        (TermKind::Integer(n), TermKind::Integer(m)) => Some(Partial::new(integer(n + m), bindings.clone(), at_rule)),
-        _ => None
+    print!("{}", args[0]);
+    Some(TRUE.into())
+}
+
+fn builtin_op_3(machine: &mut Machine, term: &Term) -> Option<Term> {
+    let args = term.get_args()?;
+
+    let mut operators = machine.db.operators_mut();
+    let result = define_operators(&mut operators, &args[0], &args[1], &args[2]);
+    drop(operators);
+    check_result(machine, result, "op", 3)
+}
+
+fn builtin_is_2(machine: &mut Machine, term: &Term) -> Option<Term> {
+    let args = term.get_args()?;
+
+    let rhs = match arithmetic::evaluate(&args[1]) {
+        Ok(result) => result.into_term(),
+        Err(formal) => {
+            machine.throw(error(formal, "is", 2));
+            return None;
+        },
+    };
+
+    machine.trace(format_args!("Evaluated {} to {}", args[1], rhs));
+    match unify_term(&args[0], &rhs, &mut machine.bindings) {
+        true => Some(rhs),
+        false => None,
     }
 }
 
-fn builtin_subtract_2(_db: &Database, term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
+fn builtin_equal_2(machine: &mut Machine, term: &Term) -> Option<Term> {
     let args = term.get_args()?;
 
-    println!("Subtracting {} with {}", &args[0], &args[1]);
-    match (&*args[0], &*args[1]) {
-        (TermKind::Integer(n), TermKind::Integer(m)) => Some(Partial::new(integer(n - m), bindings.clone(), at_rule)),
-        _ => None
+    machine.trace(format_args!("Unifying {} with {}", &args[0], &args[1]));
+    match unify_term(&args[0], &args[1], &mut machine.bindings) {
+        true => Some(TRUE.into()),
+        false => None,
     }
 }
 
-fn builtin_multiply_2(_db: &Database, term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
+fn builtin_not_equal_2(machine: &mut Machine, term: &Term) -> Option<Term> {
     let args = term.get_args()?;
 
-    println!("Adding {} with {}", &args[0], &args[1]);
-    match (&*args[0], &*args[1]) {
-        (TermKind::Integer(n), TermKind::Integer(m)) => Some(Partial::new(integer(n * m), bindings.clone(), at_rule)),
-        _ => None
+    machine.trace(format_args!("Unifying {} with {}", &args[0], &args[1]));
+    match unifiable(&args[0], &args[1], &machine.bindings) {
+        true => None,
+        false => Some(TRUE.into()),
     }
 }
 
-fn builtin_divide_2(_db: &Database, term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
+/// Compare the values of two arithmetic expressions, raising an error if either can't be evaluated
+fn compare_arithmetic(machine: &mut Machine, term: &Term, test: fn(Ordering) -> bool) -> Option<Term> {
     let args = term.get_args()?;
 
-    println!("Adding {} with {}", &args[0], &args[1]);
-    match (&*args[0], &*args[1]) {
-        (TermKind::Integer(n), TermKind::Integer(m)) => Some(Partial::new(integer(n / m), bindings.clone(), at_rule)),
-        _ => None
+    machine.trace(format_args!("Comparing {} with {}", &args[0], &args[1]));
+    match arithmetic::compare(&args[0], &args[1]) {
+        Ok(ordering) if test(ordering) => Some(TRUE.into()),
+        Ok(_) => None,
+        Err(formal) => {
+            let (name, arity) = term.indicator()?;
+            machine.throw(error(formal, &name, arity));
+            None
+        },
     }
 }
 
-fn builtin_call(db: &Database, term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
+fn builtin_less_than_2(machine: &mut Machine, term: &Term) -> Option<Term> {
+    compare_arithmetic(machine, term, |ordering| ordering == Ordering::Less)
+}
+
+fn builtin_greater_than_2(machine: &mut Machine, term: &Term) -> Option<Term> {
+    compare_arithmetic(machine, term, |ordering| ordering == Ordering::Greater)
+}
+
+fn builtin_less_than_or_equal_2(machine: &mut Machine, term: &Term) -> Option<Term> {
+    compare_arithmetic(machine, term, |ordering| ordering != Ordering::Greater)
+}
+
+fn builtin_greater_than_or_equal_2(machine: &mut Machine, term: &Term) -> Option<Term> {
+    compare_arithmetic(machine, term, |ordering| ordering != Ordering::Less)
+}
+
+fn builtin_arithmetic_equal_2(machine: &mut Machine, term: &Term) -> Option<Term> {
+    compare_arithmetic(machine, term, |ordering| ordering == Ordering::Equal)
+}
+
+fn builtin_arithmetic_not_equal_2(machine: &mut Machine, term: &Term) -> Option<Term> {
+    compare_arithmetic(machine, term, |ordering| ordering != Ordering::Equal)
+}
+
+fn builtin_call(machine: &mut Machine, term: &Term) -> Option<Term> {
     let (first, args) = term.get_args()?.split_at(1);
 
     let result = match &*first[0] {
-        TermKind::Atom(x) if args.len() == 0 => TermKind::Atom(x.to_string()),
-        TermKind::Atom(x) => TermKind::Compound(x.to_string(), args.to_vec()),
-        TermKind::Compound(x, first_args) => TermKind::Compound(x.to_string(), [first_args, args].concat()),
-        _ => return None,
+        TermKind::Atom(_) if args.is_empty() => first[0].clone(),
+        TermKind::Atom(x) => Rc::new(TermKind::Compound(*x, args.to_vec())),
+        TermKind::Compound(x, first_args) => Rc::new(TermKind::Compound(*x, [first_args, args].concat())),
+        TermKind::Var(_) => {
+            machine.throw(error(instantiation_error(), "call", args.len() + 1));
+            return None;
+        },
+        _ => {
+            machine.throw(error(type_error("callable", first[0].clone()), "call", args.len() + 1));
+            return None;
+        },
     };
 
-    Query::new(Box::new(result)).solve(db)
+    machine.push_goal(result.into());
+    Some(TRUE.into())
 }
-
@@ -0,0 +1,491 @@
+
+use std::env;
+use std::fs;
+use std::io::{ self, Read, Write, IsTerminal };
+use std::path::PathBuf;
+
+
+/// The most entries kept in the history file
+const HISTORY_LIMIT: usize = 1000;
+
+/// A key read from the terminal, with the escape sequences sent by the arrow and editing keys decoded
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    /// A control character, given by the lowercase letter typed with the control key
+    Ctrl(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Unknown,
+}
+
+fn read_byte<R: Read>(input: &mut R) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match input.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+/// Read a single key from the input, returning None at the end of the input
+pub fn read_key<R: Read>(input: &mut R) -> io::Result<Option<Key>> {
+    let byte = match read_byte(input)? {
+        Some(byte) => byte,
+        None => return Ok(None),
+    };
+
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        8 | 127 => Key::Backspace,
+        0x1b => read_escape(input)?,
+        1..=26 => Key::Ctrl((b'a' + byte - 1) as char),
+        0..=0x1f => Key::Unknown,
+        0x20..=0x7f => Key::Char(byte as char),
+        _ => {
+            // The number of bytes in a UTF-8 character is given by its first byte
+            let length = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+
+            let mut bytes = vec!(byte);
+            while bytes.len() < length {
+                match read_byte(input)? {
+                    Some(byte) => bytes.push(byte),
+                    None => break,
+                }
+            }
+            match std::str::from_utf8(&bytes).ok().and_then(|text| text.chars().next()) {
+                Some(ch) => Key::Char(ch),
+                None => Key::Unknown,
+            }
+        },
+    };
+    Ok(Some(key))
+}
+
+/// Decode the rest of an escape sequence, like `ESC [ A` for the up arrow, or `ESC [ 3 ~` for delete
+fn read_escape<R: Read>(input: &mut R) -> io::Result<Key> {
+    let kind = match read_byte(input)? {
+        Some(kind @ b'[') | Some(kind @ b'O') => kind,
+        _ => return Ok(Key::Unknown),
+    };
+
+    let mut byte = read_byte(input)?;
+    let mut number = String::new();
+    while let Some(digit @ b'0'..=b'9') = byte {
+        number.push(digit as char);
+        byte = read_byte(input)?;
+    }
+
+    let key = match (kind, byte, number.as_str()) {
+        (_, Some(b'A'), _) => Key::Up,
+        (_, Some(b'B'), _) => Key::Down,
+        (_, Some(b'C'), _) => Key::Right,
+        (_, Some(b'D'), _) => Key::Left,
+        (_, Some(b'H'), _) => Key::Home,
+        (_, Some(b'F'), _) => Key::End,
+        (b'[', Some(b'~'), "1") | (b'[', Some(b'~'), "7") => Key::Home,
+        (b'[', Some(b'~'), "4") | (b'[', Some(b'~'), "8") => Key::End,
+        (b'[', Some(b'~'), "3") => Key::Delete,
+        _ => Key::Unknown,
+    };
+    Ok(key)
+}
+
+/// A possible completion of the name before the cursor
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Completion {
+    /// The text which replaces the name, like `member(`
+    pub text: String,
+    /// How the completion is shown when listing the choices, like `member/2`
+    pub display: String,
+}
+
+/// The text of the line being edited, along with the position of the cursor in it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LineBuffer {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl LineBuffer {
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    /// Replace the text of the line, moving the cursor to the end of it
+    pub fn set(&mut self, text: &str) {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    /// Returns the name, made of letters, digits, and underscores, which ends at the cursor
+    pub fn name_before_cursor(&self) -> String {
+        let start = self.chars[..self.cursor].iter().rposition(|ch| !ch.is_alphanumeric() && *ch != '_').map(|i| i + 1).unwrap_or(0);
+        self.chars[start..self.cursor].iter().collect()
+    }
+
+    /// Extend the name before the cursor as far as all of the given completions agree, returning false if nothing
+    /// could be added
+    pub fn complete(&mut self, completions: &[Completion]) -> bool {
+        let name: Vec<char> = self.name_before_cursor().chars().collect();
+        let mut common: Vec<char> = match completions.first() {
+            Some(completion) => completion.text.chars().collect(),
+            None => return false,
+        };
+        for completion in completions.iter().skip(1) {
+            let length = common.iter().zip(completion.text.chars()).take_while(|(a, b)| **a == *b).count();
+            common.truncate(length);
+        }
+
+        if common.len() <= name.len() || !common.starts_with(&name) {
+            return false;
+        }
+        for ch in common[name.len()..].iter() {
+            self.edit(Key::Char(*ch));
+        }
+        true
+    }
+
+    /// Apply an editing key to the line, returning false if the key doesn't edit the line
+    pub fn edit(&mut self, key: Key) -> bool {
+        match key {
+            Key::Char(ch) => {
+                self.chars.insert(self.cursor, ch);
+                self.cursor += 1;
+            },
+            Key::Backspace => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.chars.remove(self.cursor);
+                }
+            },
+            Key::Delete => {
+                if self.cursor < self.chars.len() {
+                    self.chars.remove(self.cursor);
+                }
+            },
+            Key::Left | Key::Ctrl('b') => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right | Key::Ctrl('f') => self.cursor = (self.cursor + 1).min(self.chars.len()),
+            Key::Home | Key::Ctrl('a') => self.cursor = 0,
+            Key::End | Key::Ctrl('e') => self.cursor = self.chars.len(),
+            // Kill the text after the cursor, or before it
+            Key::Ctrl('k') => self.chars.truncate(self.cursor),
+            Key::Ctrl('u') => {
+                self.chars.drain(..self.cursor);
+                self.cursor = 0;
+            },
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// A line read by the editor, or the reason there wasn't one
+enum Line {
+    Text(String),
+    /// The line was abandoned with Ctrl-C
+    Interrupted,
+    Eof,
+}
+
+/// Reads queries from the user, with line editing and history when the input is a terminal, or plain reads otherwise
+pub struct Editor {
+    history: Vec<String>,
+    history_file: Option<PathBuf>,
+    terminal: bool,
+}
+
+impl Editor {
+    /// Create an editor which loads and saves its history in a file in the user's home directory
+    pub fn new() -> Self {
+        let history_file = env::var_os("HOME").map(|home| PathBuf::from(home).join(".conlog_history"));
+        Editor::with_history_file(history_file)
+    }
+
+    fn with_history_file(history_file: Option<PathBuf>) -> Self {
+        let mut history: Vec<String> = history_file.as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|contents| contents.lines().filter(|line| !line.trim().is_empty()).map(String::from).collect())
+            .unwrap_or_default();
+        let excess = history.len().saturating_sub(HISTORY_LIMIT);
+        history.drain(..excess);
+
+        Editor {
+            history,
+            history_file,
+            terminal: io::stdin().is_terminal() && io::stdout().is_terminal(),
+        }
+    }
+
+    /// Read a query, which continues over as many lines as it takes to reach one ending with a `.`.  Pressing Tab
+    /// completes the name before the cursor using the completions returned for it.  Returns None at the end of the
+    /// input
+    pub fn read_query(&mut self, complete: &dyn Fn(&str) -> Vec<Completion>) -> Option<String> {
+        let mut query = String::new();
+        loop {
+            let prompt = match query.is_empty() {
+                true => "?- ",
+                false => "|    ",
+            };
+
+            match self.read_line(prompt, complete) {
+                Line::Text(text) => {
+                    if query.is_empty() && text.trim().is_empty() {
+                        continue;
+                    }
+                    if !query.is_empty() {
+                        query.push('\n');
+                    }
+                    query.push_str(&text);
+
+                    if query.trim_end().ends_with('.') {
+                        self.add_history(&query);
+                        return Some(query);
+                    }
+                },
+                Line::Interrupted => query.clear(),
+                // Return an unfinished query, so the error in it is reported, before ending at the next read
+                Line::Eof if query.trim().is_empty() => return None,
+                Line::Eof => return Some(query),
+            }
+        }
+    }
+
+    /// Wait for a single key, without the user having to press enter if the input is a terminal.  Otherwise the
+    /// first character of the next line is used, or Enter if it's empty.  Returns None at the end of the input
+    pub fn read_key(&mut self) -> Option<Key> {
+        io::stdout().flush().ok()?;
+        if self.terminal {
+            if let Some(_raw) = termios::RawMode::enable() {
+                return read_key(&mut io::stdin().lock()).ok()?;
+            }
+        }
+
+        let mut input = String::new();
+        match io::stdin().read_line(&mut input) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(input.trim_end_matches(&['\r', '\n'][..]).chars().next().map(Key::Char).unwrap_or(Key::Enter)),
+        }
+    }
+
+    fn read_line(&mut self, prompt: &str, complete: &dyn Fn(&str) -> Vec<Completion>) -> Line {
+        if self.terminal {
+            if let Some(_raw) = termios::RawMode::enable() {
+                return self.edit_line(prompt, complete).unwrap_or(Line::Eof);
+            }
+        }
+
+        print!("{}", prompt);
+        io::stdout().flush().unwrap();
+        let mut input = String::new();
+        match io::stdin().read_line(&mut input) {
+            Ok(0) | Err(_) => Line::Eof,
+            Ok(_) => Line::Text(input.trim_end_matches(&['\r', '\n'][..]).to_string()),
+        }
+    }
+
+    /// Edit a line in raw mode, redrawing it after each key
+    fn edit_line(&mut self, prompt: &str, complete: &dyn Fn(&str) -> Vec<Completion>) -> io::Result<Line> {
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        let mut output = io::stdout();
+
+        let mut line = LineBuffer::default();
+        // The position in the history of the line being shown, where the end of the history is the new line
+        let mut position = self.history.len();
+        let mut new_line = String::new();
+
+        refresh(&mut output, prompt, &line)?;
+        loop {
+            let key = match read_key(&mut input)? {
+                Some(key) => key,
+                None => return Ok(Line::Eof),
+            };
+
+            match key {
+                Key::Enter => {
+                    writeln!(output)?;
+                    return Ok(Line::Text(line.text()));
+                },
+                Key::Ctrl('c') => {
+                    writeln!(output, "^C")?;
+                    return Ok(Line::Interrupted);
+                },
+                Key::Ctrl('d') if line.is_empty() => {
+                    writeln!(output)?;
+                    return Ok(Line::Eof);
+                },
+                Key::Ctrl('d') => { line.edit(Key::Delete); },
+                Key::Tab => {
+                    // Only names starting with a lowercase letter are completed, since the others are variables
+                    let name = line.name_before_cursor();
+                    if name.starts_with(|ch: char| ch.is_lowercase()) {
+                        let completions = complete(&name);
+                        if !line.complete(&completions) && completions.len() > 1 {
+                            let choices: Vec<&str> = completions.iter().map(|completion| completion.display.as_str()).collect();
+                            write!(output, "\n{}\n", choices.join("  "))?;
+                        }
+                    }
+                },
+                Key::Up | Key::Ctrl('p') if position > 0 => {
+                    if position == self.history.len() {
+                        new_line = line.text();
+                    }
+                    position -= 1;
+                    line.set(&self.history[position]);
+                },
+                Key::Down | Key::Ctrl('n') if position < self.history.len() => {
+                    position += 1;
+                    match self.history.get(position) {
+                        Some(entry) => line.set(entry),
+                        None => line.set(&new_line),
+                    }
+                },
+                key => { line.edit(key); },
+            }
+            refresh(&mut output, prompt, &line)?;
+        }
+    }
+
+    /// Add a query to the history, and append it to the history file, or rewrite the file with only the most recent
+    /// entries once it's reached the limit.  Queries which span lines are joined into one
+    fn add_history(&mut self, query: &str) {
+        if !self.terminal {
+            return;
+        }
+
+        let entry = query.lines().map(str::trim).collect::<Vec<&str>>().join(" ");
+        if self.history.last() == Some(&entry) {
+            return;
+        }
+        self.history.push(entry.clone());
+        let excess = self.history.len().saturating_sub(HISTORY_LIMIT);
+        self.history.drain(..excess);
+
+        if let Some(path) = &self.history_file {
+            // The file can have more entries than were loaded from it, so it's rewritten whenever the limit is reached
+            if excess > 0 {
+                let contents: String = self.history.iter().map(|entry| format!("{}\n", entry)).collect();
+                let _ = fs::write(path, contents);
+                return;
+            }
+
+            let file = fs::OpenOptions::new().create(true).append(true).open(path);
+            if let Ok(mut file) = file {
+                let _ = writeln!(file, "{}", entry);
+            }
+        }
+    }
+}
+
+/// Redraw the prompt and line, and then move the cursor back to its position in the line
+fn refresh<W: Write>(output: &mut W, prompt: &str, line: &LineBuffer) -> io::Result<()> {
+    write!(output, "\r{}{}\x1b[K\r", prompt, line.text())?;
+    let column = prompt.chars().count() + line.cursor();
+    if column > 0 {
+        write!(output, "\x1b[{}C", column)?;
+    }
+    output.flush()
+}
+
+/// Switching the terminal into raw mode, so keys can be read as they're pressed instead of a line at a time.  The
+/// layout of the termios struct is specific to each platform, so raw mode is only available on Linux
+#[cfg(target_os = "linux")]
+mod termios {
+    use std::os::raw::c_int;
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    struct Termios {
+        c_iflag: u32,
+        c_oflag: u32,
+        c_cflag: u32,
+        c_lflag: u32,
+        c_line: u8,
+        c_cc: [u8; 32],
+        c_ispeed: u32,
+        c_ospeed: u32,
+    }
+
+    extern "C" {
+        fn tcgetattr(fd: c_int, termios: *mut Termios) -> c_int;
+        fn tcsetattr(fd: c_int, action: c_int, termios: *const Termios) -> c_int;
+    }
+
+    const STDIN: c_int = 0;
+    const TCSADRAIN: c_int = 1;
+
+    const ISIG: u32 = 0o1;
+    const ICANON: u32 = 0o2;
+    const ECHO: u32 = 0o10;
+    const IEXTEN: u32 = 0o100000;
+    const BRKINT: u32 = 0o2;
+    const INPCK: u32 = 0o20;
+    const ISTRIP: u32 = 0o40;
+    const ICRNL: u32 = 0o400;
+    const IXON: u32 = 0o2000;
+    const VTIME: usize = 5;
+    const VMIN: usize = 6;
+
+    /// Keeps the terminal in raw mode until it's dropped, when the original settings are restored
+    pub struct RawMode(Termios);
+
+    impl RawMode {
+        /// Put the terminal into raw mode, or return None if it can't be
+        pub fn enable() -> Option<RawMode> {
+            let mut original = Termios { c_iflag: 0, c_oflag: 0, c_cflag: 0, c_lflag: 0, c_line: 0, c_cc: [0; 32], c_ispeed: 0, c_ospeed: 0 };
+            if unsafe { tcgetattr(STDIN, &mut original) } != 0 {
+                return None;
+            }
+
+            // Output processing is left on, so that newlines still return the cursor to the start of the line
+            let mut raw = original;
+            raw.c_lflag &= !(ECHO | ICANON | IEXTEN | ISIG);
+            raw.c_iflag &= !(BRKINT | INPCK | ISTRIP | ICRNL | IXON);
+            raw.c_cc[VMIN] = 1;
+            raw.c_cc[VTIME] = 0;
+            if unsafe { tcsetattr(STDIN, TCSADRAIN, &raw) } != 0 {
+                return None;
+            }
+            Some(RawMode(original))
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            unsafe { tcsetattr(STDIN, TCSADRAIN, &self.0) };
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod termios {
+    pub struct RawMode;
+
+    impl RawMode {
+        pub fn enable() -> Option<RawMode> {
+            None
+        }
+    }
+}
@@ -0,0 +1,42 @@
+
+use crate::tree::{ Term, atom, integer, compound };
+
+
+/// Wrap an ISO error term like `type_error(integer, a)` in `error(Formal, Context)`, using the predicate indicator of
+/// the builtin that raised it as the context
+pub fn error(formal: Term, name: &str, arity: usize) -> Term {
+    compound("error", vec!(formal, compound("context", vec!(indicator(name, arity), atom("_")))))
+}
+
+/// Returns a predicate indicator term like `foo/2`
+pub fn indicator(name: &str, arity: usize) -> Term {
+    compound("/", vec!(atom(name), integer(arity as i64)))
+}
+
+pub fn instantiation_error() -> Term {
+    atom("instantiation_error")
+}
+
+pub fn type_error(kind: &str, culprit: Term) -> Term {
+    compound("type_error", vec!(atom(kind), culprit))
+}
+
+pub fn evaluation_error(kind: &str) -> Term {
+    compound("evaluation_error", vec!(atom(kind)))
+}
+
+pub fn resource_error(kind: &str) -> Term {
+    compound("resource_error", vec!(atom(kind)))
+}
+
+pub fn domain_error(kind: &str, culprit: Term) -> Term {
+    compound("domain_error", vec!(atom(kind), culprit))
+}
+
+pub fn existence_error(kind: &str, culprit: Term) -> Term {
+    compound("existence_error", vec!(atom(kind), culprit))
+}
+
+pub fn permission_error(action: &str, kind: &str, culprit: Term) -> Term {
+    compound("permission_error", vec!(atom(action), atom(kind), culprit))
+}
@@ -0,0 +1,75 @@
+
+use std::fmt;
+use std::str::FromStr;
+
+
+/// What to do when a goal calls a predicate that has no clauses defined
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Unknown {
+    /// Raise `existence_error(procedure, Name/Arity)`
+    Error,
+    /// Fail silently
+    Fail,
+    /// Print a warning and then fail
+    Warning,
+}
+
+impl FromStr for Unknown {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Unknown::Error),
+            "fail" => Ok(Unknown::Fail),
+            "warning" => Ok(Unknown::Warning),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Unknown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Unknown::Error => write!(f, "error"),
+            Unknown::Fail => write!(f, "fail"),
+            Unknown::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// The flags which can be changed with `set_prolog_flag/2` to alter the behaviour of the solver
+#[derive(Copy, Clone, Debug)]
+pub struct Flags {
+    pub unknown: Unknown,
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Flags {
+            unknown: Unknown::Error,
+        }
+    }
+}
+
+impl Flags {
+    pub const NAMES: &'static [&'static str] = &["unknown"];
+
+    /// Returns the value of the named flag as an atom, or None if there's no such flag
+    pub fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "unknown" => Some(self.unknown.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Set the named flag from an atom, returning false if the value isn't valid for the flag
+    pub fn set(&mut self, name: &str, value: &str) -> bool {
+        match name {
+            "unknown" => match Unknown::from_str(value) {
+                Ok(unknown) => { self.unknown = unknown; true },
+                Err(_) => false,
+            },
+            _ => false,
+        }
+    }
+}
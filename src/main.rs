@@ -1,20 +1,30 @@
 
-use std::io;
+use std::fs;
 use std::env;
-use std::fs::File;
-use std::io::{ Read, Write };
+use std::process;
 
-mod misc;
+mod atoms;
 mod tree;
+mod bigint;
+mod errors;
 mod parser;
+mod operators;
+mod flags;
 mod solver;
+mod predicate;
 mod builtins;
+mod writer;
+mod arithmetic;
+mod editor;
 mod tests;
 
 #[allow(unused_imports)]
 use tree::{ TermKind, Clause, variable, atom, compound, conjunct, fact, rule };
-use parser::{ parse, parse_query };
-use solver::{ Database, Query, Partial };
+use parser::{ parse, parse_query, parse_recovering, parse_query_with };
+use operators::Operators;
+use solver::{ Database, Query, Solutions };
+use editor::{ Editor, Key, Completion };
+use builtins::builtin_indicators;
 
 fn run_default() {
     /*
@@ -37,7 +47,6 @@ fn run_default() {
 
     /*
     let input = "
-        not(X) :- X, !, fail.
         thing.
         has(thing) :- not(thing).
     ";
@@ -55,10 +64,9 @@ fn run_default() {
 
     /*
     let input = "
-        not(X) :- X, !, fail.
         delete(X, [], []).
         delete(X, [X|Ys], Zs) :- delete(X, Ys, Zs).
-        delete(X, [Y|Ys], [Y|Zs]) :- not(equal(X, Y)), delete(X, Ys, Zs).
+        delete(X, [Y|Ys], [Y|Zs]) :- \\+ X = Y, delete(X, Ys, Zs).
     ";
 
     let query_string = "
@@ -78,71 +86,159 @@ fn run_default() {
 
     let clauses = parse(input).unwrap();
     println!("{:?}", clauses);
-    let db = Database::new(clauses);
+    let mut db = Database::new(clauses);
+    db.set_trace(true);
 
     let query_term = parse_query(query_string).unwrap();
     println!("{:?}", query_term);
     let query = Query::new(query_term);
 
-    let mut at_rule = 0;
+    let mut solutions = query.solutions(&db);
     for _ in 0..5 {
-        let partial = query.solve_from(&db, at_rule);
-        match partial {
-            Some(partial) => {
-                println!("Result: \x1b[32m{}\x1b[0m", partial.result);
-                at_rule = partial.rule + 1;
+        match solutions.next() {
+            Some(solution) => {
+                println!("\x1b[32m{}.\x1b[0m", solution.answer);
             },
             None => {
-                println!("Result: \x1b[31mfalse\x1b[0m");
+                println!("\x1b[31mfalse.\x1b[0m");
                 break;
             },
         }
     }
 }
 
-fn load_database(filename: &str) -> Database {
-    let mut file = File::open(filename).unwrap();
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
+/// Read and parse a program file, returning the clauses that were parsed along with a message for each problem found
+fn read_program(filename: &str) -> (Vec<Clause>, Vec<String>) {
+    let contents = match fs::read_to_string(filename) {
+        Ok(contents) => contents,
+        Err(err) => return (vec!(), vec!(format!("{}: {}", filename, err))),
+    };
 
-    let clauses = parse(&contents).unwrap();
-    println!("{:?}", clauses);
-    Database::new(clauses)
+    let (clauses, errors) = parse_recovering(&contents, &mut Operators::default());
+    let diagnostics = errors.iter().map(|err| format!("{}: {}", filename, err)).collect();
+    (clauses, diagnostics)
 }
 
-fn run_query(db: &Database, query: &str) -> Option<Partial> {
-    let query_term = parse_query(query).ok()?;
-    println!("{:?}", query_term);
+fn load_database(filename: &str, trace: bool) -> Database {
+    let mut db = Database::new(vec!());
+    db.set_trace(trace);
+    if let Err(error) = db.consult(filename) {
+        println!("\x1b[31mError: {}\x1b[0m", error);
+    }
+    db
+}
+
+/// Check a program file for syntax errors without running it, returning true if there were none
+fn lint(filename: &str) -> bool {
+    let (clauses, diagnostics) = read_program(filename);
+    for diagnostic in diagnostics.iter() {
+        println!("{}", diagnostic);
+    }
+    println!("{}: {} clauses, {} errors", filename, clauses.len(), diagnostics.len());
+    diagnostics.is_empty()
+}
+
+fn run_query<'db>(db: &'db Database, query: &str) -> Result<Solutions<'db>, String> {
+    let query_term = parse_query_with(query, &db.operators()).map_err(|err| err.to_string())?;
     let query = Query::new(query_term);
-    query.solve(db)
+    Ok(query.solutions(db))
 }
 
-fn repl(db: Database) {
+/// Print each answer to a query in turn, asking after each one whether to look for another, until there are no more
+/// solutions or the user stops
+fn print_answers(editor: &mut Editor, mut solutions: Solutions) {
     loop {
-        let mut input = String::new();
-        io::stdout().write_all(b"?- ").unwrap();
-        io::stdout().flush().unwrap();
-        match io::stdin().read_line(&mut input) {
-            Ok(_) => {
-                match run_query(&db, &input) {
-                    Some(partial) =>
-                        println!("Result: \x1b[32m{}\x1b[0m", partial.result),
-                    None =>
-                        println!("Error"),
+        match solutions.next() {
+            Some(solution) => {
+                print!("\x1b[32m{}\x1b[0m", solution.answer);
+                // Only ask for more if there's somewhere left to look for them
+                if !solutions.has_alternatives() || !ask_for_more(editor) {
+                    println!(".");
+                    return;
+                }
+                println!(";");
+            },
+            None => {
+                match solutions.error() {
+                    Some(error) => println!("\x1b[31mUncaught error: {}\x1b[0m", error),
+                    None => println!("\x1b[31mfalse.\x1b[0m"),
                 }
-            }
-            Err(err) => println!("IO Error: {:?}", err),
+                return;
+            },
+        }
+    }
+}
+
+/// Wait for the user to enter `;` or a space for the next answer, or `.` or Enter to stop
+fn ask_for_more(editor: &mut Editor) -> bool {
+    loop {
+        print!(" ");
+        match editor.read_key() {
+            Some(Key::Char(';')) | Some(Key::Char(' ')) => return true,
+            Some(Key::Char('.')) | Some(Key::Enter) | Some(Key::Ctrl('c')) | None => return false,
+            _ => print!("\nEnter ; for the next answer or . to stop"),
+        }
+    }
+}
+
+/// Returns the completions of a name typed at the prompt, from the predicates in the database and the builtins, which
+/// are shown with their arity, and the atoms used in the database
+fn completions(db: &Database, prefix: &str) -> Vec<Completion> {
+    let predicates = db.predicate_indicators().into_iter().map(|(name, arity)| (name.as_str(), arity)).chain(builtin_indicators());
+    let mut completions: Vec<Completion> = predicates
+        .filter(|(name, _)| name.starts_with(prefix))
+        .map(|(name, arity)| Completion {
+            text: match arity {
+                0 => name.to_string(),
+                _ => format!("{}(", name),
+            },
+            display: format!("{}/{}", name, arity),
+        })
+        .collect();
+
+    // Only atoms which can be written without quotes are offered, and not those already offered as predicates
+    let atoms: Vec<Completion> = db.atoms().into_iter()
+        .filter(|name| name.starts_with(prefix) && name.chars().all(|ch| ch.is_alphanumeric() || ch == '_'))
+        .filter(|name| !completions.iter().any(|completion| completion.text == name.as_str()))
+        .map(|name| Completion { text: name.to_string(), display: name.to_string() })
+        .collect();
+    completions.extend(atoms);
+
+    completions.sort();
+    completions.dedup();
+    completions
+}
+
+fn repl(db: Database) {
+    let mut editor = Editor::new();
+    while let Some(input) = editor.read_query(&|prefix| completions(&db, prefix)) {
+        match run_query(&db, &input) {
+            Ok(solutions) => print_answers(&mut editor, solutions),
+            Err(err) => println!("\x1b[31m{}\x1b[0m", err),
         }
     }
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    let trace = args.iter().any(|arg| arg == "--trace");
+    args.retain(|arg| arg != "--trace");
+
+    if args.iter().any(|arg| arg == "--lint") {
+        args.retain(|arg| arg != "--lint");
+        // Check every file, rather than stopping at the first one with errors
+        let results = args.iter().map(|filename| lint(filename)).collect::<Vec<bool>>();
+        if results.contains(&false) {
+            process::exit(1);
+        }
+        return;
+    }
 
-    if args.len() <= 1 {
+    if args.is_empty() {
         run_default();
     } else {
-        let db = load_database(&args[1]);
+        let db = load_database(&args[0], trace);
         repl(db);
     }
 }
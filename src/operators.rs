@@ -0,0 +1,140 @@
+
+use std::str::FromStr;
+use std::collections::HashMap;
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OperatorType {
+    Xfx,
+    Xfy,
+    Yfx,
+    Fy,
+    Fx,
+    Xf,
+    Yf,
+}
+
+impl OperatorType {
+    pub fn is_prefix(self) -> bool {
+        matches!(self, OperatorType::Fy | OperatorType::Fx)
+    }
+
+    pub fn is_postfix(self) -> bool {
+        matches!(self, OperatorType::Xf | OperatorType::Yf)
+    }
+
+    /// Returns the maximum priorities allowed for the left and right arguments of an operator with the given
+    /// priority, or 0 for a side that doesn't have an argument
+    pub fn argument_priorities(self, priority: usize) -> (usize, usize) {
+        let below = priority.saturating_sub(1);
+        match self {
+            OperatorType::Xfx => (below, below),
+            OperatorType::Xfy => (below, priority),
+            OperatorType::Yfx => (priority, below),
+            OperatorType::Fy => (0, priority),
+            OperatorType::Fx => (0, below),
+            OperatorType::Xf => (below, 0),
+            OperatorType::Yf => (priority, 0),
+        }
+    }
+}
+
+impl FromStr for OperatorType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xfx" => Ok(OperatorType::Xfx),
+            "xfy" => Ok(OperatorType::Xfy),
+            "yfx" => Ok(OperatorType::Yfx),
+            "fy" => Ok(OperatorType::Fy),
+            "fx" => Ok(OperatorType::Fx),
+            "xf" => Ok(OperatorType::Xf),
+            "yf" => Ok(OperatorType::Yf),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Operator {
+    pub priority: usize,
+    pub kind: OperatorType,
+}
+
+/// The table of operators used when parsing.  A name can be defined as a prefix operator, and as either an infix
+/// or a postfix operator at the same time, like `-` which is both prefix and infix
+#[derive(Clone, Debug)]
+pub struct Operators {
+    prefix: HashMap<String, Operator>,
+    infix: HashMap<String, Operator>,
+    postfix: HashMap<String, Operator>,
+}
+
+const ISO_OPERATORS: &[(usize, OperatorType, &[&str])] = &[
+    (1200, OperatorType::Xfx, &[":-", "-->"]),
+    (1200, OperatorType::Fx, &[":-", "?-"]),
+    (1150, OperatorType::Fx, &["dynamic", "discontiguous", "initialization", "multifile"]),
+    (1100, OperatorType::Xfy, &[";"]),
+    (1105, OperatorType::Xfy, &["|"]),
+    (1050, OperatorType::Xfy, &["->", "*->"]),
+    (1000, OperatorType::Xfy, &[","]),
+    (900, OperatorType::Fy, &["\\+"]),
+    (700, OperatorType::Xfx, &["=", "\\=", "==", "\\==", "@<", "@>", "@=<", "@>=", "=..", "is", "=:=", "=\\=", "<", ">", "=<", ">=", "<="]),
+    (600, OperatorType::Xfy, &[":"]),
+    (500, OperatorType::Yfx, &["+", "-", "/\\", "\\/", "xor"]),
+    (400, OperatorType::Yfx, &["*", "/", "//", "rem", "mod", "div", "<<", ">>"]),
+    (200, OperatorType::Xfx, &["**"]),
+    (200, OperatorType::Xfy, &["^"]),
+    (200, OperatorType::Fy, &["-", "+", "\\"]),
+];
+
+impl Default for Operators {
+    /// Create a table with the standard ISO operators defined
+    fn default() -> Self {
+        let mut operators = Operators {
+            prefix: HashMap::new(),
+            infix: HashMap::new(),
+            postfix: HashMap::new(),
+        };
+
+        for (priority, kind, names) in ISO_OPERATORS {
+            for name in names.iter() {
+                operators.add(*priority, *kind, name);
+            }
+        }
+        operators
+    }
+}
+
+impl Operators {
+    /// Define an operator, replacing any existing definition of the same class (prefix, infix, or postfix).  A
+    /// priority of 0 removes the definition instead
+    pub fn add(&mut self, priority: usize, kind: OperatorType, name: &str) {
+        let table = if kind.is_prefix() {
+            &mut self.prefix
+        } else if kind.is_postfix() {
+            &mut self.postfix
+        } else {
+            &mut self.infix
+        };
+
+        if priority == 0 {
+            table.remove(name);
+        } else {
+            table.insert(name.to_string(), Operator { priority, kind });
+        }
+    }
+
+    pub fn prefix(&self, name: &str) -> Option<Operator> {
+        self.prefix.get(name).copied()
+    }
+
+    pub fn infix(&self, name: &str) -> Option<Operator> {
+        self.infix.get(name).copied()
+    }
+
+    pub fn postfix(&self, name: &str) -> Option<Operator> {
+        self.postfix.get(name).copied()
+    }
+}
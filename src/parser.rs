@@ -1,12 +1,18 @@
-
+use std::fmt;
 use std::str::Chars;
+use std::str::FromStr;
 use std::iter::Peekable;
 
-use crate::tree::{ Term, TermKind, Expr, ExprKind, Clause, empty_list, cons_list };
+use crate::tree::{ Term, TermKind, Clause, variable, atom, integer, big_integer, float, string, compound, empty_list, cons_list };
+use crate::bigint::BigInt;
+use crate::operators::{ Operators, OperatorType };
+use crate::errors::{ instantiation_error, type_error, domain_error };
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
     Word(String),
+    Quoted(String),
+    Functor(String),
     String(String),
     OpenBracket,
     CloseBracket,
@@ -14,94 +20,232 @@ pub enum Token {
     CloseSquare,
     VerticalBar,
     Comma,
-    Horn,
     Period,
 }
 
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(name) | Token::Functor(name) => write!(f, "`{}`", name),
+            Token::Quoted(name) => write!(f, "`'{}'`", name),
+            Token::String(string) => write!(f, "`\"{}\"`", string),
+            Token::OpenBracket => write!(f, "`(`"),
+            Token::CloseBracket => write!(f, "`)`"),
+            Token::OpenSquare => write!(f, "`[`"),
+            Token::CloseSquare => write!(f, "`]`"),
+            Token::VerticalBar => write!(f, "`|`"),
+            Token::Comma => write!(f, "`,`"),
+            Token::Period => write!(f, "`.`"),
+        }
+    }
+}
+
+/// The location of a token in the source text, as a range of byte offsets, and the line and column (both starting
+/// from 1) of its first character
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
 pub struct Lexer<'input> {
     chars: Peekable<Chars<'input>>,
+    offset: usize,
+    line: usize,
+    column: usize,
 }
 
 impl<'input> Lexer<'input> {
     pub fn new(input: &'input str) -> Self {
         Lexer {
             chars: input.chars().peekable(),
+            offset: 0,
+            line: 1,
+            column: 1,
         }
     }
 
-    pub fn get_token(&mut self) -> Option<Token> {
+    /// Returns the span of an empty token at the current position, such as the end of the input
+    pub fn current_span(&self) -> Span {
+        Span { start: self.offset, end: self.offset, line: self.line, column: self.column }
+    }
+
+    pub fn get_token(&mut self) -> Option<(Token, Span)> {
         self.eat_whitespace();
 
-        match self.chars.next()? {
-            '(' => Some(Token::OpenBracket),
-            ')' => Some(Token::CloseBracket),
-            '[' => Some(Token::OpenSquare),
-            ']' => Some(Token::CloseSquare),
-            '|' => Some(Token::VerticalBar),
-            '.' => Some(Token::Period),
-            ',' => Some(Token::Comma),
-
-            '\'' => {
-                let string = self.get_string(None, |ch| ch != '\'');
-                self.chars.next();      // Eat the remaining \' character
-                Some(Token::Word(string))
-            },
+        let start = self.current_span();
+        let token = self.get_token_kind()?;
+        let span = Span { end: self.offset, ..start };
+        Some((token, span))
+    }
 
-            '\"' => {
-                let string = self.get_string(None, |ch| ch != '\"');
-                self.chars.next();      // Eat the remaining \" character
-                Some(Token::String(string))
-            },
+    fn get_token_kind(&mut self) -> Option<Token> {
+        let token = match self.next_char()? {
+            '(' => Token::OpenBracket,
+            ')' => Token::CloseBracket,
+            '[' => Token::OpenSquare,
+            ']' => Token::CloseSquare,
+            '|' => Token::VerticalBar,
+            ',' => Token::Comma,
+            '!' => Token::Word("!".to_string()),
+            ';' => Token::Word(";".to_string()),
+
+            // A period is only the end of a clause when followed by whitespace, otherwise it's part of an operator
+            '.' if self.is_end() => Token::Period,
+
+            '\'' => Token::Quoted(self.get_quoted('\'')),
+            '\"' => return Some(Token::String(self.get_quoted('\"'))),
 
             '%' => {
                 // Ignore comment lines, which start with a '%' character
-                while self.chars.next_if(|ch| *ch != '\n').is_some() { }
-                self.get_token()
+                while self.next_char_if(|ch| ch != '\n').is_some() { }
+                self.eat_whitespace();
+                return self.get_token_kind();
+            },
+
+            ch if ch.is_ascii_digit() => {
+                Token::Word(self.get_number(ch))
             },
 
             ch if is_word(ch) => {
-                Some(Token::Word(self.get_string(Some(ch), is_word)))
+                Token::Word(self.get_string(Some(ch), is_word))
             },
 
             ch => {
-                match self.get_string(Some(ch), is_operator) {
-                    op if op.as_str() == ":-" => Some(Token::Horn),
-                    op => Some(Token::Word(op)),
-                }
+                Token::Word(self.get_symbol(ch))
             },
+        };
+
+        // A name immediately followed by an open bracket (with no whitespace) is the functor of a compound term
+        match token {
+            Token::Word(name) | Token::Quoted(name) if self.chars.peek() == Some(&'(') => Some(Token::Functor(name)),
+            token => Some(token),
+        }
+    }
+
+    /// Consume the next character, updating the current position
+    fn next_char(&mut self) -> Option<char> {
+        let ch = self.chars.next()?;
+        self.offset += ch.len_utf8();
+        if ch == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(ch)
+    }
+
+    fn next_char_if(&mut self, f: impl Fn(char) -> bool) -> Option<char> {
+        match self.chars.peek() {
+            Some(ch) if f(*ch) => self.next_char(),
+            _ => None,
         }
     }
 
     fn eat_whitespace(&mut self) {
-        while self.chars.next_if(|ch| is_whitespace(*ch)).is_some() { }
+        while self.next_char_if(is_whitespace).is_some() { }
+    }
+
+    fn is_end(&mut self) -> bool {
+        match self.chars.peek() {
+            None => true,
+            Some(ch) => is_whitespace(*ch) || *ch == '%',
+        }
     }
 
     fn get_string(&mut self, first: Option<char>, f: impl Fn(char) -> bool) -> String {
-        let mut text = first.map(|s| s.to_string()).unwrap_or(String::new());
-        while let Some(ch) = self.chars.next_if(|ch| f(*ch)) {
+        let mut text = first.map(|s| s.to_string()).unwrap_or_default();
+        while let Some(ch) = self.next_char_if(&f) {
+            text.push(ch);
+        }
+        text
+    }
+
+    /// Read the rest of a quoted atom or string, up to and including the closing quote.  The quote is included in the
+    /// text by doubling it, and a backslash starts an escape sequence for a quote, a backslash, a newline or a tab
+    fn get_quoted(&mut self, quote: char) -> String {
+        let mut text = String::new();
+        while let Some(ch) = self.next_char() {
+            match ch {
+                ch if ch == quote => match self.next_char_if(|next| next == quote) {
+                    Some(_) => text.push(quote),
+                    None => break,
+                },
+                '\\' => match self.next_char_if(|next| ['\\', '\'', '"', 'n', 't'].contains(&next)) {
+                    Some('n') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    Some(escaped) => text.push(escaped),
+                    None => text.push('\\'),
+                },
+                ch => text.push(ch),
+            }
+        }
+        text
+    }
+
+    /// Returns the character the given number of places past the next one, without consuming anything
+    fn peek_ahead(&self, n: usize) -> Option<char> {
+        self.chars.clone().nth(n)
+    }
+
+    fn get_number(&mut self, first: char) -> String {
+        let mut text = self.get_string(Some(first), |ch| ch.is_ascii_digit());
+
+        // A period followed by a digit is a decimal point rather than the end of the clause
+        if self.chars.peek() == Some(&'.') && self.peek_ahead(1).is_some_and(|ch| ch.is_ascii_digit()) {
+            self.next_char();
+            text.push('.');
+            text.push_str(&self.get_string(None, |ch| ch.is_ascii_digit()));
+        }
+
+        if matches!(self.chars.peek(), Some('e') | Some('E')) {
+            let sign = matches!(self.peek_ahead(1), Some('+') | Some('-'));
+            let digit = if sign { 2 } else { 1 };
+            if self.peek_ahead(digit).is_some_and(|ch| ch.is_ascii_digit()) {
+                for _ in 0..digit {
+                    text.push(self.next_char().unwrap());
+                }
+                text.push_str(&self.get_string(None, |ch| ch.is_ascii_digit()));
+            }
+        }
+        text
+    }
+
+    fn get_symbol(&mut self, first: char) -> String {
+        let mut text = first.to_string();
+        while let Some(ch) = self.chars.peek().copied() {
+            if ch == '.' {
+                // Look one character past the period, and stop if it would be the end of the clause
+                if self.peek_ahead(1).map(|ch| is_whitespace(ch) || ch == '%').unwrap_or(true) {
+                    break;
+                }
+            } else if !is_operator(ch) {
+                break;
+            }
             text.push(ch);
+            self.next_char();
         }
         text
     }
 }
 
 fn is_whitespace(ch: char) -> bool {
-    ch == ' ' || ch == '\n' || ch == '\t'
+    ch == ' ' || ch == '\n' || ch == '\t' || ch == '\r'
 }
 
-fn is_word(ch: char) -> bool {
-    ('a'..='z').contains(&ch) || ('A'..='Z').contains(&ch) || ('0'..='9').contains(&ch) || (ch == '_')
+pub fn is_word(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || (ch == '_')
 }
 
-fn is_operator(ch: char) -> bool {
-    match ch {
-        ';' | ':' | '=' | '>' | '<' | '+' | '-' | '*' | '\\' | '/' | '!' | '#' | '$' | '?' | '@' | '^' => true,
-        _ => false,
-    }
+pub fn is_operator(ch: char) -> bool {
+    matches!(ch, ':' | '=' | '>' | '<' | '+' | '-' | '*' | '\\' | '/' | '#' | '$' | '?' | '@' | '^' | '&' | '~' | '.')
 }
 
 impl<'input> Iterator for Lexer<'input> {
-    type Item = Token;
+    type Item = (Token, Span);
 
     fn next(&mut self) -> Option<Self::Item> {
         self.get_token()
@@ -111,60 +255,164 @@ impl<'input> Iterator for Lexer<'input> {
 
 
 
-#[derive(Clone, Debug)]
-pub enum ParseError {
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseErrorKind {
     UnexpectedEof,
     UnexpectedToken(Token),
 }
 
+/// An error found while parsing, along with where it occurred, which tokens would have been accepted instead, and
+/// the line of source text it was found on, so that it can be displayed with a caret under the offending token
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Span,
+    pub expected: Vec<String>,
+    pub source_line: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: ", self.span.line, self.span.column)?;
+        match &self.kind {
+            ParseErrorKind::UnexpectedEof => write!(f, "unexpected end of input")?,
+            ParseErrorKind::UnexpectedToken(token) => write!(f, "unexpected {}", token)?,
+        }
+
+        if let Some((last, rest)) = self.expected.split_last() {
+            match rest.is_empty() {
+                true => write!(f, ", expected {}", last)?,
+                false => write!(f, ", expected {} or {}", rest.join(", "), last)?,
+            }
+        }
+
+        // Copy any tabs before the token so that the caret lines up with it
+        let indent: String = self.source_line
+            .chars()
+            .take(self.span.column - 1)
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+        let remaining = self.source_line.chars().count().saturating_sub(self.span.column - 1);
+        let width = (self.span.end - self.span.start).min(remaining).max(1);
+        write!(f, "\n{}\n{}{}", self.source_line, indent, "^".repeat(width))
+    }
+}
+
+/// The tokens from a lexer, along with the span of the last token taken, so that errors can refer to it
+pub struct TokenStream<'input> {
+    text: &'input str,
+    lexer: Peekable<Lexer<'input>>,
+    span: Span,
+}
+
+impl<'input> TokenStream<'input> {
+    pub fn new(text: &'input str) -> Self {
+        TokenStream {
+            text,
+            lexer: Lexer::new(text).peekable(),
+            span: Span::default(),
+        }
+    }
+
+    pub fn peek(&mut self) -> Option<&Token> {
+        self.lexer.peek().map(|(token, _)| token)
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<Token> {
+        match self.lexer.next() {
+            Some((token, span)) => {
+                self.span = span;
+                Some(token)
+            },
+            None => {
+                self.span = self.end_span();
+                None
+            },
+        }
+    }
+
+    /// Create an error for the last token taken from the stream
+    pub fn error(&self, kind: ParseErrorKind, expected: &[&str]) -> ParseError {
+        let line_start = self.text[..self.span.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = self.text[self.span.start..].find('\n').map(|i| i + self.span.start).unwrap_or(self.text.len());
+
+        ParseError {
+            kind,
+            span: self.span,
+            expected: expected.iter().map(|s| s.to_string()).collect(),
+            source_line: self.text[line_start..line_end].trim_end_matches('\r').to_string(),
+        }
+    }
+
+    fn end_span(&self) -> Span {
+        let offset = self.text.len();
+        let line_start = self.text.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        Span {
+            start: offset,
+            end: offset,
+            line: self.text.matches('\n').count() + 1,
+            column: self.text[line_start..].chars().count() + 1,
+        }
+    }
+}
+
 #[inline(always)]
-fn expect_next(input: &mut Peekable<Lexer>) -> Result<Token, ParseError> {
-    input.next().ok_or(ParseError::UnexpectedEof)
+fn expect_next(input: &mut TokenStream, expected: &[&str]) -> Result<Token, ParseError> {
+    input.next().ok_or_else(|| input.error(ParseErrorKind::UnexpectedEof, expected))
 }
 
 #[inline]
-fn expect_token(input: &mut Peekable<Lexer>, token: Token) -> Result<(), ParseError> {
-    let next = expect_next(input)?;
+fn expect_token(input: &mut TokenStream, token: Token) -> Result<(), ParseError> {
+    let description = token.to_string();
+    let next = expect_next(input, &[&description])?;
     match next == token {
         true => Ok(()),
-        false => Err(ParseError::UnexpectedToken(next)),
+        false => Err(unexpected(input, next, &[&description])),
     }
 }
 
-fn parse_number(name: String) -> Result<i64, ParseError> {
-    let (num, _) = name.chars().rev().fold((0, 1), |acc, ch| {
-        match ch {
-            '-' => (-acc.0, acc.1),
-            _ => ((ch.to_digit(10).unwrap() as i64 * acc.1) + acc.0, acc.1 * 10)
-        }
-    });
-    Ok(num)
+fn unexpected(input: &TokenStream, token: Token, expected: &[&str]) -> ParseError {
+    input.error(ParseErrorKind::UnexpectedToken(token), expected)
+}
+
+fn parse_number(name: String) -> Result<Term, ParseError> {
+    if name.contains(['.', 'e', 'E']) {
+        return Ok(float(name.parse().unwrap()));
+    }
+
+    // Integers too large for an i64 are stored as big integers instead
+    match name.parse::<i64>() {
+        Ok(num) => Ok(integer(num)),
+        Err(_) => Ok(big_integer(BigInt::parse(&name).unwrap())),
+    }
 }
 
 fn parse_atom_or_variable(name: String) -> Result<Term, ParseError> {
     match name.chars().next() {
-        Some(ch) if ('0'..='9').contains(&ch) || ch == '-' => {
-            Ok(Box::new(TermKind::Integer(parse_number(name)?)))
+        Some(ch) if ch.is_ascii_digit() || (ch == '-' && name[1..].starts_with(|ch: char| ch.is_ascii_digit())) => {
+            parse_number(name)
         },
-        Some(ch) if ('A'..='Z').contains(&ch) =>
-            Ok(Box::new(TermKind::Var(name))),
+        Some(ch) if ch.is_ascii_uppercase() =>
+            Ok(variable(&name)),
+        // A name starting with an underscore is a variable, except for `_` on its own which is still a wildcard
+        Some('_') if name.len() > 1 =>
+            Ok(variable(&name)),
         _ =>
-            Ok(Box::new(TermKind::Atom(name))),
+            Ok(atom(&name)),
     }
 }
 
-fn parse_compound(input: &mut Peekable<Lexer>, name: String) -> Result<Term, ParseError> {
-    match expect_next(input)? {
-        Token::OpenBracket => {
-            let args = parse_comma_separated(input)?;
-            expect_token(input, Token::CloseBracket)?;
-            Ok(Box::new(TermKind::Compound(name, args)))
-        },
-        token => Err(ParseError::UnexpectedToken(token)),
+fn parse_compound(input: &mut TokenStream, operators: &Operators, name: String) -> Result<Term, ParseError> {
+    expect_token(input, Token::OpenBracket)?;
+    let args = parse_comma_separated(input, operators)?;
+    match expect_next(input, &["`,`", "`)`"])? {
+        Token::CloseBracket => Ok(compound(&name, args)),
+        token => Err(unexpected(input, token, &["`,`", "`)`"])),
     }
 }
 
-fn parse_list(input: &mut Peekable<Lexer>) -> Result<Term, ParseError> {
+fn parse_list(input: &mut TokenStream, operators: &Operators) -> Result<Term, ParseError> {
     if let Some(Token::CloseSquare) = input.peek() {
         input.next();
         return Ok(empty_list());
@@ -174,19 +422,19 @@ fn parse_list(input: &mut Peekable<Lexer>) -> Result<Term, ParseError> {
     let mut terms = empty_list();
 
     loop {
-        new_list.push(parse_term(input)?);
+        new_list.push(parse_term(input, operators, ARGUMENT_PRIORITY)?);
 
-        match expect_next(input)? {
+        match expect_next(input, &["`,`", "`|`", "`]`"])? {
             Token::Comma => { /* continue the loop */ },
             Token::CloseSquare => {
                 break;
             },
             Token::VerticalBar => {
-                terms = parse_term(input)?;
+                terms = parse_term(input, operators, ARGUMENT_PRIORITY)?;
                 expect_token(input, Token::CloseSquare)?;
                 break;
             },
-            token => return Err(ParseError::UnexpectedToken(token)),
+            token => return Err(unexpected(input, token, &["`,`", "`|`", "`]`"])),
         }
     }
 
@@ -198,11 +446,11 @@ fn parse_list(input: &mut Peekable<Lexer>) -> Result<Term, ParseError> {
     Ok(terms)
 }
 
-fn parse_comma_separated(input: &mut Peekable<Lexer>) -> Result<Vec<Term>, ParseError> {
+fn parse_comma_separated(input: &mut TokenStream, operators: &Operators) -> Result<Vec<Term>, ParseError> {
     let mut list = vec!();
 
     loop {
-        list.push(parse_term(input)?);
+        list.push(parse_term(input, operators, ARGUMENT_PRIORITY)?);
         match input.peek() {
             Some(Token::Comma) => { input.next(); },
             _ => { break; },
@@ -212,85 +460,239 @@ fn parse_comma_separated(input: &mut Peekable<Lexer>) -> Result<Vec<Term>, Parse
     Ok(list)
 }
 
-const OPERATORS: [&str; 10] = [ ",", "=", "\\=", ">", ">=", "<", "<=", "+", "-", "is" ];
+/// The highest priority a term can have, which is the priority of a whole clause
+const MAX_PRIORITY: usize = 1200;
+/// The highest priority of an argument to a compound term or an item in a list, which is just below `,`
+pub(crate) const ARGUMENT_PRIORITY: usize = 999;
+
+fn is_terminator(token: &Token) -> bool {
+    matches!(token, Token::Comma | Token::CloseBracket | Token::CloseSquare | Token::VerticalBar | Token::Period)
+}
+
+/// Returns the name of the token if it could be an infix or postfix operator
+fn operator_name(token: &Token) -> Option<&str> {
+    match token {
+        Token::Word(name) => Some(name),
+        Token::Comma => Some(","),
+        Token::VerticalBar => Some("|"),
+        _ => None,
+    }
+}
+
+/// Returns true if the token can start an operand, rather than being an infix operator or the end of the term
+fn starts_operand(token: &Token, operators: &Operators) -> bool {
+    match token {
+        Token::Word(name) => (operators.infix(name).is_none() && operators.postfix(name).is_none()) || operators.prefix(name).is_some(),
+        token => !is_terminator(token),
+    }
+}
+
+/// Parse a term whose priority is no greater than `max`, using operator precedence (Pratt) parsing
+fn parse_term(input: &mut TokenStream, operators: &Operators, max: usize) -> Result<Term, ParseError> {
+    let (mut term, mut priority) = parse_primary(input, operators, max)?;
+
+    while let Some(token) = input.peek() {
+        let name = match operator_name(token) {
+            Some(name) => name.to_string(),
+            None => break,
+        };
+
+        if let Some(op) = operators.infix(&name) {
+            let (left, right) = op.kind.argument_priorities(op.priority);
+            if op.priority <= max && priority <= left {
+                input.next();
+                let rhs = parse_term(input, operators, right)?;
+                // As in ISO Prolog, a bar used as an infix operator of priority 1100 or more is read as a disjunction
+                let name = match name.as_str() {
+                    "|" if op.priority >= 1100 => ";",
+                    name => name,
+                };
+                term = compound(name, vec!(term, rhs));
+                priority = op.priority;
+                continue;
+            }
+        }
 
-fn parse_term(input: &mut Peekable<Lexer>) -> Result<Term, ParseError> {
-    let term = match expect_next(input)? {
-        Token::String(string) => Ok(Box::new(TermKind::String(string))),
+        if let Some(op) = operators.postfix(&name) {
+            let (left, _) = op.kind.argument_priorities(op.priority);
+            if op.priority <= max && priority <= left {
+                input.next();
+                term = compound(&name, vec!(term));
+                priority = op.priority;
+                continue;
+            }
+        }
+
+        break;
+    }
+
+    Ok(term)
+}
+
+/// Parse a single operand, which might be a prefix operator applied to another operand, and return it along with
+/// its priority
+fn parse_primary(input: &mut TokenStream, operators: &Operators, max: usize) -> Result<(Term, usize), ParseError> {
+    match expect_next(input, &["a term"])? {
+        Token::String(text) => Ok((string(text), 0)),
+        Token::Functor(name) => Ok((parse_compound(input, operators, name)?, 0)),
+        Token::Quoted(name) => Ok((atom(&name), 0)),
         Token::Word(name) => {
-            match input.peek() {
-                Some(Token::OpenBracket) =>
-                    parse_compound(input, name),
-                _ =>
-                    parse_atom_or_variable(name),
+            let op = match (operators.prefix(&name), input.peek()) {
+                (Some(op), Some(token)) if starts_operand(token, operators) => op,
+                _ => return Ok((parse_atom_or_variable(name)?, 0)),
+            };
+
+            // A minus sign directly before a number is a negative number rather than an operator
+            if name == "-" {
+                if let Some(Token::Word(number)) = input.peek() {
+                    if number.starts_with(|ch: char| ch.is_ascii_digit()) {
+                        let number = format!("-{}", number);
+                        input.next();
+                        return Ok((parse_atom_or_variable(number)?, 0));
+                    }
+                }
             }
+
+            let priority = op.priority.min(max);
+            let (_, right) = op.kind.argument_priorities(priority);
+            let operand = parse_term(input, operators, right)?;
+            Ok((compound(&name, vec!(operand)), priority))
         },
         Token::OpenSquare => {
-            parse_list(input)
+            Ok((parse_list(input, operators)?, 0))
         },
-        token => Err(ParseError::UnexpectedToken(token)),
-    };
-
-    match input.peek() {
-        Some(Token::Word(name)) if OPERATORS.iter().any(|s| *s == name) => {
-            let name = name.to_string();
-            input.next();
-            Ok(Box::new(TermKind::Compound(name, vec!(term?, parse_term(input)?))))
+        Token::OpenBracket => {
+            let term = parse_term(input, operators, MAX_PRIORITY)?;
+            expect_token(input, Token::CloseBracket)?;
+            Ok((term, 0))
         },
-        _ => term,
+        token => Err(unexpected(input, token, &["a term"])),
     }
 }
 
-fn parse_expression(input: &mut Peekable<Lexer>) -> Result<Expr, ParseError> {
-    let term = Box::new(ExprKind::Term(parse_term(input)?));
-    match input.peek() {
-        Some(Token::Comma) => {
-            input.next();
-            Ok(Box::new(ExprKind::Conjunct(term, parse_expression(input)?)))
-        },
-        _ => Ok(term),
+/// Expect the period at the end of a clause or query, which is the only thing other than an operator that can follow
+/// a complete term
+fn expect_end(input: &mut TokenStream) -> Result<(), ParseError> {
+    match expect_next(input, &["an operator", "`.`"])? {
+        Token::Period => Ok(()),
+        token => Err(unexpected(input, token, &["an operator", "`.`"])),
     }
 }
 
-fn parse_clause(input: &mut Peekable<Lexer>) -> Result<Clause, ParseError> {
-    let term = parse_term(input)?;
+fn parse_clause(input: &mut TokenStream, operators: &Operators) -> Result<Clause, ParseError> {
+    let term = parse_term(input, operators, MAX_PRIORITY)?;
+    expect_end(input)?;
 
-    match expect_next(input)? {
-        Token::Period => Ok(Clause::Fact(term)),
-        Token::Horn => {
-            let expr = parse_expression(input)?;
-            expect_token(input, Token::Period)?;
-            Ok(Clause::Rule(term, expr))
+    match &*term {
+        TermKind::Compound(name, args) if name == ":-" && args.len() == 2 =>
+            Ok(Clause::Rule(args[0].clone(), args[1].clone().into())),
+        TermKind::Compound(name, args) if name == ":-" && args.len() == 1 =>
+            Ok(Clause::Directive(args[0].clone())),
+        _ => Ok(Clause::Fact(term)),
+    }
+}
+
+/// Apply an `op(Priority, Type, Names)` directive to the operator table, returning the ISO error term if the
+/// arguments are malformed.  Names in a list before an invalid one will have already been defined
+pub fn define_operators(operators: &mut Operators, priority: &Term, kind: &Term, names: &Term) -> Result<(), Term> {
+    let priority = match &**priority {
+        TermKind::Var(_) => return Err(instantiation_error()),
+        TermKind::Integer(value) if (0..=1200).contains(value) => *value as usize,
+        TermKind::Integer(_) => return Err(domain_error("operator_priority", priority.clone())),
+        _ => return Err(type_error("integer", priority.clone())),
+    };
+
+    let kind = match &**kind {
+        TermKind::Var(_) => return Err(instantiation_error()),
+        TermKind::Atom(name) => match OperatorType::from_str(name) {
+            Ok(kind) => kind,
+            Err(_) => return Err(domain_error("operator_specifier", kind.clone())),
         },
-        _ => Err(ParseError::UnexpectedEof),
+        _ => return Err(type_error("atom", kind.clone())),
+    };
+
+    let mut list = names;
+    loop {
+        match &**list {
+            TermKind::Atom(name) => {
+                operators.add(priority, kind, name);
+                return Ok(());
+            },
+            TermKind::List(head, tail) => {
+                match &**head {
+                    TermKind::Atom(name) => operators.add(priority, kind, name),
+                    TermKind::Var(_) => return Err(instantiation_error()),
+                    _ => return Err(type_error("atom", head.clone())),
+                }
+                list = tail;
+            },
+            TermKind::EmptyList => return Ok(()),
+            TermKind::Var(_) => return Err(instantiation_error()),
+            _ => return Err(type_error("list", names.clone())),
+        }
     }
 }
 
 pub fn parse(text: &str) -> Result<Vec<Clause>, ParseError> {
-    let mut input = Lexer::new(text).peekable();
+    parse_with(text, &mut Operators::default())
+}
+
+/// Parse a program using the given operator table, which is updated by any `op/3` directives as they're parsed so
+/// that they apply to the rest of the text.  Returns the first error found, if any
+pub fn parse_with(text: &str, operators: &mut Operators) -> Result<Vec<Clause>, ParseError> {
+    let (clauses, mut errors) = parse_recovering(text, operators);
+    match errors.is_empty() {
+        true => Ok(clauses),
+        false => Err(errors.swap_remove(0)),
+    }
+}
+
+/// Parse a program, recovering from any errors by skipping to the end of the clause they occur in, and returning
+/// all the clauses that were successfully parsed along with every error found
+pub fn parse_recovering(text: &str, operators: &mut Operators) -> (Vec<Clause>, Vec<ParseError>) {
+    let mut input = TokenStream::new(text);
 
     let mut clauses = vec!();
+    let mut errors = vec!();
     loop {
         if input.peek().is_none() {
             break;
         }
 
-        let clause = parse_clause(&mut input)?;
-        clauses.push(clause);
+        match parse_clause(&mut input, operators) {
+            Ok(clause) => {
+                if let Clause::Directive(goal) = &clause {
+                    if let TermKind::Compound(name, args) = &**goal {
+                        if name == "op" && args.len() == 3 {
+                            let _ = define_operators(operators, &args[0], &args[1], &args[2]);
+                        }
+                    }
+                }
+                clauses.push(clause);
+            },
+            Err(err) => {
+                // Skip the rest of the clause, unless the error was the period at the end of it
+                if err.kind != ParseErrorKind::UnexpectedToken(Token::Period) {
+                    while !matches!(input.next(), Some(Token::Period) | None) { }
+                }
+                errors.push(err);
+            },
+        }
     }
 
-    Ok(clauses)
+    (clauses, errors)
 }
 
 pub fn parse_query(text: &str) -> Result<Term, ParseError> {
-    let mut input = Lexer::new(text).peekable();
+    parse_query_with(text, &Operators::default())
+}
 
-    let term = parse_term(&mut input)?;
+/// Parse a query, which can be any term that's allowed as the body of a clause, including conjunctions, disjunctions,
+/// and if-then-else, which are interpreted when the query is solved
+pub fn parse_query_with(text: &str, operators: &Operators) -> Result<Term, ParseError> {
+    let mut input = TokenStream::new(text);
 
-    match input.next() {
-        Some(Token::Period) => Ok(term),
-        Some(token) => Err(ParseError::UnexpectedToken(token)),
-        None => Err(ParseError::UnexpectedEof),
-    }
+    let term = parse_term(&mut input, operators, MAX_PRIORITY)?;
+    expect_end(&mut input)?;
+    Ok(term)
 }
-
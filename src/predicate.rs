@@ -0,0 +1,480 @@
+
+use std::rc::Rc;
+use std::ops::Range;
+use std::iter::FromIterator;
+use std::convert::TryFrom;
+use std::cell::{ Cell, OnceCell, RefCell };
+use std::collections::{ HashMap, HashSet, VecDeque };
+
+use crate::tree::{ Term, TermKind, Variable, Expr, map_variables, map_expr_variables };
+use crate::atoms::Atom;
+
+
+/// A clause stored in the database, along with the generations of the database it was added and removed in, so that
+/// a call only sees the clauses that were in the database when it was made
+pub struct StoredClause {
+    pub head: Term,
+    pub body: Option<Expr>,
+    /// The number of distinct variables in the clause, which are numbered from 1
+    pub variables: usize,
+    pub generation: u64,
+    /// The generation the clause was removed in, if it has been removed
+    pub erased: Cell<Option<u64>>,
+}
+
+impl StoredClause {
+    /// Create a stored clause, numbering its variables so that each use of the clause can rename them by adding an
+    /// offset to their ids.  Variables in an asserted clause can come from different renamings of the same name, so
+    /// they're told apart by their ids as well as their names
+    pub fn new(head: Term, body: Option<Expr>, generation: u64) -> Self {
+        let mut ids: HashMap<Variable, Term> = HashMap::new();
+        let mut number = |var: Variable| {
+            let id = ids.len() + 1;
+            ids.entry(var).or_insert_with(|| Rc::new(TermKind::Var(Variable { name: var.name, id }))).clone()
+        };
+
+        let head = map_variables(&head, &mut number);
+        let body = body.map(|body| map_expr_variables(&body, &mut number));
+        StoredClause {
+            head,
+            body,
+            variables: ids.len(),
+            generation,
+            erased: Cell::new(None),
+        }
+    }
+
+    /// Returns true if the clause was in the database in the given generation
+    pub fn is_visible(&self, generation: u64) -> bool {
+        self.generation <= generation && self.erased.get().is_none_or(|erased| erased > generation)
+    }
+}
+
+/// The part of a clause's first argument that's used to index it.  Two terms with different keys will never unify
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    Atom(Atom),
+    Integer(i64),
+    Functor(Atom, usize),
+    EmptyList,
+    List,
+}
+
+impl Key {
+    /// Returns the key for a term, or None if it's a variable, which could match any key, or a kind of term which
+    /// isn't indexed
+    pub fn of(term: &Term) -> Option<Key> {
+        match &**term {
+            TermKind::Atom(name) if *name == "_" => None,
+            TermKind::Atom(name) => Some(Key::Atom(*name)),
+            TermKind::Integer(num) => Some(Key::Integer(*num)),
+            TermKind::Compound(name, args) => Some(Key::Functor(*name, args.len())),
+            TermKind::EmptyList => Some(Key::EmptyList),
+            TermKind::List(_, _) => Some(Key::List),
+            _ => None,
+        }
+    }
+}
+
+/// The positions of some of the clauses of a predicate, in order.  Each position keeps the same place in the list as
+/// clauses are added at either end, so that a call can carry on from its place in the list after clauses are added
+#[derive(Clone, Default)]
+pub struct ClauseList {
+    positions: VecDeque<usize>,
+    /// The place of the first position in the list, which goes down as clauses are added at the start
+    start: isize,
+}
+
+impl ClauseList {
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Returns the places of the positions in the list, in order
+    pub fn places(&self) -> Range<isize> {
+        self.start..self.start + self.positions.len() as isize
+    }
+
+    /// Returns the position at the given place in the list, or None if the place is past either end of it
+    pub fn get(&self, place: isize) -> Option<usize> {
+        usize::try_from(place - self.start).ok().and_then(|i| self.positions.get(i)).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &usize> {
+        self.positions.iter()
+    }
+
+    fn push(&mut self, position: usize, at_start: bool) {
+        match at_start {
+            true => {
+                self.positions.push_front(position);
+                self.start -= 1;
+            },
+            false => self.positions.push_back(position),
+        }
+    }
+
+    /// Drop the positions of removed clauses from the ends of the list, so that clauses which are removed from the
+    /// start or end of a predicate, as they usually are, don't leave gaps for calls to skip
+    fn trim(&mut self, clauses: &[Option<Rc<StoredClause>>]) {
+        while matches!(self.positions.front(), Some(&position) if clauses[position].is_none()) {
+            self.positions.pop_front();
+            self.start += 1;
+        }
+        while matches!(self.positions.back(), Some(&position) if clauses[position].is_none()) {
+            self.positions.pop_back();
+        }
+    }
+}
+
+impl FromIterator<usize> for ClauseList {
+    fn from_iter<I: IntoIterator<Item = usize>>(positions: I) -> Self {
+        ClauseList { positions: positions.into_iter().collect(), start: 0 }
+    }
+}
+
+/// An index of the clauses of a predicate by the key of one of their arguments
+#[derive(Default)]
+pub struct ArgumentIndex {
+    /// The positions of the clauses which might match a goal whose argument has the given key, which includes every
+    /// clause whose argument is a variable
+    keys: HashMap<Key, ClauseList>,
+    /// The positions of the clauses whose argument is a variable, which are the only ones that can match a goal whose
+    /// key isn't in the index
+    variable: ClauseList,
+}
+
+impl ArgumentIndex {
+    fn build(clauses: &[Option<Rc<StoredClause>>], all: &ClauseList, argument: usize) -> Self {
+        let mut index = ArgumentIndex::default();
+        for &position in all.iter() {
+            if let Some(clause) = &clauses[position] {
+                index.add(position, &clause.head, argument, false);
+            }
+        }
+        index
+    }
+
+    /// Returns the positions of the clauses which might match a goal whose argument has the given key, in order
+    pub fn select(&self, key: &Key) -> &ClauseList {
+        self.keys.get(key).unwrap_or(&self.variable)
+    }
+
+    /// Returns the number of distinct keys in the index
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Add the clause at the given position to the start or end of the lists it belongs in
+    fn add(&mut self, position: usize, head: &Term, argument: usize, at_start: bool) {
+        let push = |positions: &mut ClauseList| positions.push(position, at_start);
+
+        let arg = match argument_of(head, argument) {
+            Some(arg) => arg,
+            None => return,
+        };
+
+        match Key::of(arg) {
+            Some(key) => {
+                let variable_positions = &self.variable;
+                push(self.keys.entry(key).or_insert_with(|| variable_positions.clone()));
+            },
+            // A clause whose argument is a variable can match any key
+            None if is_variable(arg) => {
+                push(&mut self.variable);
+                for positions in self.keys.values_mut() {
+                    push(positions);
+                }
+            },
+            // Any other kind of argument, like a float, can only match a goal which isn't indexed
+            None => { },
+        }
+    }
+
+    /// Update the lists the given clause belongs in after it's been removed
+    fn remove(&mut self, head: &Term, argument: usize, clauses: &[Option<Rc<StoredClause>>]) {
+        let arg = match argument_of(head, argument) {
+            Some(arg) => arg,
+            None => return,
+        };
+
+        match Key::of(arg) {
+            Some(key) => if let Some(positions) = self.keys.get_mut(&key) {
+                positions.trim(clauses);
+                if positions.is_empty() {
+                    self.keys.remove(&key);
+                }
+            },
+            None if is_variable(arg) => {
+                self.variable.trim(clauses);
+                for positions in self.keys.values_mut() {
+                    positions.trim(clauses);
+                }
+            },
+            None => { },
+        }
+    }
+}
+
+/// The clauses of a predicate in order, along with indexes of them by their arguments.  Clauses are added and removed
+/// in place, and a call to the predicate only sees the clauses which were in it when the call was made (the logical
+/// update view), going by the generations each clause was added and removed in
+///
+/// The index on the first argument is built as soon as it's needed.  Indexes on the other arguments are only built
+/// once the predicate has enough clauses, in the style of SWI-Prolog's just-in-time indexing, and only when a call
+/// with that argument bound would otherwise have many clauses to try.  A call then builds the index on whichever of
+/// its bound arguments looks most selective from a sample of the clauses, rather than every one of them.  Each call
+/// uses whichever index selects the fewest clauses
+///
+/// Clauses keep their positions for as long as they're in the predicate, so the indexes are updated in place when
+/// clauses are added or removed.  A clause removed while calls to the predicate are still open is kept for them until
+/// there are none.  After that it leaves a gap, which calls skip over, until there are more gaps than clauses and the
+/// predicate is compacted
+#[derive(Default)]
+pub struct Predicate {
+    pub dynamic: bool,
+    /// The clauses in the order they were added, which isn't their order in the predicate if any were added at the
+    /// start, with None in place of any which have been removed
+    clauses: Vec<Option<Rc<StoredClause>>>,
+    /// The positions of every clause in order, used when none of the goal's arguments are indexed
+    all: ClauseList,
+    indexes: Vec<OnceCell<ArgumentIndex>>,
+    /// The number of clauses which have been removed but still leave a gap
+    removed: usize,
+    /// The positions of the clauses which were removed while the predicate was in use, which are kept until it isn't
+    erased: Vec<usize>,
+}
+
+/// A predicate as it's held by the database and by the calls to it which are still open
+pub type SharedPredicate = Rc<RefCell<Predicate>>;
+
+/// The number of clauses a predicate must have before indexes on arguments other than the first are built
+pub const JIT_INDEX_THRESHOLD: usize = 8;
+
+/// The number of clauses sampled to estimate how selective an index on an argument would be
+const SAMPLE_SIZE: usize = 16;
+
+/// The number of removed clauses a predicate can have before it's compacted, however few clauses it has
+const COMPACT_THRESHOLD: usize = 32;
+
+impl Predicate {
+    pub fn new(dynamic: bool, arity: usize) -> Self {
+        Predicate {
+            dynamic,
+            clauses: vec!(),
+            all: ClauseList::default(),
+            indexes: (0..arity).map(|_| OnceCell::new()).collect(),
+            removed: 0,
+            erased: vec!(),
+        }
+    }
+
+    /// Returns the clauses of the predicate in order
+    pub fn clauses(&self) -> impl Iterator<Item = &Rc<StoredClause>> {
+        self.all.iter()
+            .filter_map(move |&position| self.clauses[position].as_ref())
+            .filter(|clause| clause.erased.get().is_none())
+    }
+
+    /// Returns the number of clauses in the predicate
+    pub fn len(&self) -> usize {
+        self.clauses.len() - self.removed - self.erased.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the clause at the given position, or None if it's been removed and no calls can still see it
+    pub fn get(&self, position: usize) -> Option<&Rc<StoredClause>> {
+        self.clauses[position].as_ref()
+    }
+
+    /// Returns true if the given argument can be indexed in this predicate
+    pub fn is_indexable(&self, argument: usize) -> bool {
+        argument < self.indexes.len() && (argument == 0 || self.len() >= JIT_INDEX_THRESHOLD)
+    }
+
+    /// Returns the index on the given argument, building it if it doesn't exist yet
+    fn index(&self, argument: usize) -> &ArgumentIndex {
+        self.indexes[argument].get_or_init(|| ArgumentIndex::build(&self.clauses, &self.all, argument))
+    }
+
+    /// Returns the argument whose index selects the fewest clauses for a goal whose arguments have the given keys,
+    /// along with the positions of those clauses in order, or None and every clause if no arguments have keys
+    pub fn select(&self, keys: &[Option<Key>]) -> (Option<usize>, &ClauseList) {
+        let mut best: (Option<usize>, &ClauseList) = (None, &self.all);
+        for (argument, key) in keys.iter().enumerate() {
+            let index = match argument {
+                0 => key.as_ref().map(|_| self.index(0)),
+                _ => self.indexes[argument].get(),
+            };
+
+            if let (Some(key), Some(index)) = (key, index) {
+                let candidates = index.select(key);
+                if best.0.is_none() || candidates.len() < best.1.len() {
+                    best = (Some(argument), candidates);
+                }
+            }
+        }
+
+        // If the existing indexes still leave many clauses to try, then a new index is built on another argument
+        if best.1.len() >= JIT_INDEX_THRESHOLD {
+            if let Some(argument) = self.assess(keys, best.1.len()) {
+                if let Some(key) = &keys[argument] {
+                    let candidates = self.index(argument).select(key);
+                    if candidates.len() < best.1.len() {
+                        best = (Some(argument), candidates);
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    /// Returns the bound argument without an index whose index looks like it would select the fewest clauses, if it
+    /// would select fewer than the given number.  This is estimated from the number of distinct keys the argument has
+    /// in a sample of the clauses, so that indexes which wouldn't help aren't built
+    fn assess(&self, keys: &[Option<Key>], selected: usize) -> Option<usize> {
+        let step = (self.all.len() / SAMPLE_SIZE).max(1);
+        let mut best: Option<(usize, usize)> = None;
+        for (argument, key) in keys.iter().enumerate() {
+            if key.is_none() || self.indexes[argument].get().is_some() {
+                continue;
+            }
+
+            let sample: HashSet<Key> = self.all.iter()
+                .step_by(step)
+                .filter_map(|&position| self.clauses[position].as_ref())
+                .filter_map(|clause| argument_of(&clause.head, argument).and_then(Key::of))
+                .collect();
+
+            let distinct = sample.len();
+            if distinct > 1 && self.len() / distinct < selected && best.is_none_or(|(_, most)| distinct > most) {
+                best = Some((argument, distinct));
+            }
+        }
+        best.map(|(argument, _)| argument)
+    }
+
+    /// Returns the positions of the clauses selected by the given argument's key, as previously chosen by `select`
+    pub fn candidates(&self, argument: Option<usize>, key: Option<&Key>) -> &ClauseList {
+        match (argument, key) {
+            (Some(argument), Some(key)) => self.index(argument).select(key),
+            _ => &self.all,
+        }
+    }
+
+    /// Returns the arguments which have been indexed, along with the index of each
+    pub fn indexes(&self) -> impl Iterator<Item = (usize, &ArgumentIndex)> {
+        self.indexes.iter().enumerate().filter_map(|(argument, index)| index.get().map(|index| (argument, index)))
+    }
+
+    /// Add a clause to the end of the predicate
+    pub fn push(&mut self, clause: Rc<StoredClause>) {
+        self.add(clause, false);
+    }
+
+    /// Add a clause to the start of the predicate
+    pub fn push_front(&mut self, clause: Rc<StoredClause>) {
+        self.add(clause, true);
+    }
+
+    fn add(&mut self, clause: Rc<StoredClause>, at_start: bool) {
+        let position = self.clauses.len();
+        self.all.push(position, at_start);
+        for (argument, index) in self.indexes.iter_mut().enumerate() {
+            if let Some(index) = index.get_mut() {
+                index.add(position, &clause.head, argument, at_start);
+            }
+        }
+        self.clauses.push(Some(clause));
+    }
+
+    /// Remove the given clause, which was found at the given position, from the predicate in the given generation,
+    /// returning false if it had already been removed.  If the predicate is in use, then the clause is kept for the
+    /// calls which can still see it until the predicate is no longer in use
+    pub fn remove(&mut self, position: usize, clause: &Rc<StoredClause>, generation: u64, in_use: bool) -> bool {
+        let is_clause = |stored: &Option<Rc<StoredClause>>| stored.as_ref().is_some_and(|stored| Rc::ptr_eq(stored, clause));
+        if clause.erased.get().is_some() {
+            return false;
+        }
+
+        // The position will have changed if the predicate was compacted since the clause was found
+        let position = match self.clauses.get(position) {
+            Some(stored) if is_clause(stored) => position,
+            _ => match self.clauses.iter().position(is_clause) {
+                Some(position) => position,
+                None => return false,
+            },
+        };
+
+        clause.erased.set(Some(generation));
+        self.erased.push(position);
+        if !in_use {
+            self.purge();
+        }
+        true
+    }
+
+    /// Drop the clauses which were removed while the predicate was in use, which should only be called once no calls
+    /// to the predicate are open
+    pub fn purge(&mut self) {
+        if self.erased.is_empty() {
+            return;
+        }
+
+        for position in std::mem::take(&mut self.erased) {
+            let clause = match self.clauses[position].take() {
+                Some(clause) => clause,
+                None => continue,
+            };
+            self.removed += 1;
+            self.all.trim(&self.clauses);
+            for (argument, index) in self.indexes.iter_mut().enumerate() {
+                if let Some(index) = index.get_mut() {
+                    index.remove(&clause.head, argument, &self.clauses);
+                }
+            }
+        }
+
+        if self.removed > COMPACT_THRESHOLD && self.removed > self.len() {
+            self.compact();
+        }
+    }
+
+    /// Close the gaps left by removed clauses, renumbering the remaining clauses in order and rebuilding the indexes
+    /// which had been built
+    fn compact(&mut self) {
+        let mut clauses = std::mem::take(&mut self.clauses);
+        self.clauses = self.all.iter().filter_map(|&position| clauses[position].take()).map(Some).collect();
+        self.all = (0..self.clauses.len()).collect();
+        self.removed = 0;
+        for argument in 0..self.indexes.len() {
+            if let Some(index) = self.indexes[argument].get_mut() {
+                *index = ArgumentIndex::build(&self.clauses, &self.all, argument);
+            }
+        }
+    }
+}
+
+/// Returns the given argument of a clause's head, if it has one
+fn argument_of(head: &Term, argument: usize) -> Option<&Term> {
+    match &**head {
+        TermKind::Compound(_, args) => args.get(argument),
+        _ => None,
+    }
+}
+
+/// Returns true if the argument is a variable, or the atom `_` which is treated as one
+fn is_variable(arg: &Term) -> bool {
+    match &**arg {
+        TermKind::Var(_) => true,
+        TermKind::Atom(name) => *name == "_",
+        _ => false,
+    }
+}
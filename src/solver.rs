@@ -1,325 +1,1372 @@
-
+use std::rc::Rc;
+use std::fmt;
+use std::cell::{ Cell, Ref, RefMut, RefCell };
 use std::fmt::Debug;
-use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+use std::collections::{ HashMap, HashSet, BTreeMap };
 
-use crate::tree::{ Term, TermKind, Expr, ExprKind, Clause };
+use crate::tree::{ Term, TermKind, Variable, Expr, ExprKind, Clause, atom, integer, variable, compound, map_variables, map_expr_variables, expr_terms };
+use crate::atoms::{ Atom, TRUE, FAIL, CUT, SOFT_CUT, EXIT_CATCH };
 use crate::builtins::lookup_builtin;
-use crate::misc::UniqueID;
+use crate::operators::Operators;
+use crate::parser::parse_recovering;
+use crate::flags::{ Flags, Unknown };
+use crate::predicate::{ Predicate, SharedPredicate, StoredClause, ClauseList, Key };
+use crate::errors::{ error, indicator, instantiation_error, type_error, existence_error, permission_error };
 
 
+/// The values bound to variables, along with a trail of the order they were bound in, so that bindings made after a
+/// choice point can be undone when backtracking to it, instead of saving a copy of all bindings at each choice point
 #[derive(Clone, Debug, PartialEq)]
-pub struct Bindings(HashMap<String, Term>);
+pub struct Bindings {
+    values: HashMap<Variable, Term>,
+    trail: Vec<Variable>,
+    /// Variables with ids below this existed when the most recent choice point was created, so only their bindings
+    /// need to be trailed.  Newer variables can't be reached once the choice point is backtracked to
+    boundary: usize,
+}
 
 impl Bindings {
     pub fn empty() -> Self {
-        Bindings(HashMap::new())
+        Bindings {
+            values: HashMap::new(),
+            trail: vec!(),
+            boundary: 0,
+        }
+    }
+
+    pub fn bind(&mut self, var: Variable, term: Term) {
+        self.values.insert(var, term);
+        if var.id < self.boundary {
+            self.trail.push(var);
+        }
     }
 
-    pub fn with(name: &str, term: Term) -> Self {
-        let mut bindings = Bindings(HashMap::new());
-        bindings.0.insert(name.to_string(), term);
-        bindings
+    /// Returns the number of variables which are bound
+    pub fn len(&self) -> usize {
+        self.values.len()
     }
 
-    pub fn merge(&self, bindings: &Bindings) -> Option<Bindings> {
-        let mut new_bindings = Bindings::empty();
-        new_bindings.0.extend(self.0.clone());
-        for (key, value) in bindings.0.iter() {
-            match new_bindings.0.get(key) {
-                Some(original) if !compare_term(original, value) => {
-                    println!("Already defined {:?} as {:?} but trying to set to {:?}", key, original, value);
-                    return None;
+    /// Returns the number of bindings which are recorded in the trail
+    #[allow(dead_code)]
+    pub fn trail_len(&self) -> usize {
+        self.trail.len()
+    }
+
+    /// Remove the bindings of every variable which can't be reached from the given terms, either directly or through
+    /// the values of other variables
+    pub fn retain_reachable(&mut self, mut roots: Vec<Term>) {
+        let mut reachable = HashSet::new();
+        // The visited terms are kept alive until the end, so that their addresses can't be reused by another term
+        let mut visited: HashMap<*const TermKind, Term> = HashMap::new();
+        while let Some(term) = roots.pop() {
+            match &*term {
+                TermKind::Var(var) => {
+                    if reachable.insert(*var) {
+                        if let Some(value) = self.values.get(var) {
+                            roots.push(value.clone());
+                        }
+                    }
+                    continue;
+                },
+                TermKind::Compound(_, args) if !visited.contains_key(&Rc::as_ptr(&term)) => {
+                    roots.extend(args.iter().cloned());
                 },
-                _ => {
-                    new_bindings.0.insert(key.clone(), value.clone());
+                TermKind::List(head, tail) if !visited.contains_key(&Rc::as_ptr(&term)) => {
+                    roots.push(head.clone());
+                    roots.push(tail.clone());
                 },
+                _ => continue,
             }
+            visited.insert(Rc::as_ptr(&term), term);
         }
-        Some(new_bindings)
+
+        self.values.retain(|var, _| reachable.contains(var));
     }
 
-    pub fn substitute(&self, term: Term) -> Term {
-        Box::new(match *term {
-            TermKind::EmptyList => TermKind::EmptyList,
-            TermKind::Atom(n) => TermKind::Atom(n),
-            TermKind::Integer(num) => TermKind::Integer(num),
-            TermKind::String(string) => TermKind::String(string),
-            TermKind::Compound(n, args) => {
-                let args = args.iter().map(|t| self.substitute(t.clone())).collect();
-                TermKind::Compound(n, args)
-            },
-            TermKind::List(head, tail) => {
-                TermKind::List(self.substitute(head), self.substitute(tail))
+    /// Returns a mark which can later be passed to `undo_to` to remove all bindings made after this point
+    pub fn mark(&self) -> usize {
+        self.trail.len()
+    }
+
+    pub fn undo_to(&mut self, mark: usize) {
+        while self.trail.len() > mark {
+            if let Some(var) = self.trail.pop() {
+                self.values.remove(&var);
+            }
+        }
+    }
+
+    /// Follow the chain of variable bindings until reaching either a non-variable term or an unbound variable
+    pub fn resolve<'a>(&'a self, mut term: &'a Term) -> &'a Term {
+        while let TermKind::Var(var) = &**term {
+            match self.values.get(var) {
+                Some(value) => { term = value; },
+                None => break,
+            }
+        }
+        term
+    }
+
+    /// Replace the bound variables in a term with their values.  Any parts of the term which don't contain bound
+    /// variables are shared with the original term rather than copied
+    pub fn substitute(&self, term: &Term) -> Term {
+        let term = self.resolve(term);
+        match &**term {
+            TermKind::Compound(name, args) => {
+                let substituted: Vec<Term> = args.iter().map(|arg| self.substitute(arg)).collect();
+                match substituted.iter().zip(args.iter()).all(|(new, old)| Rc::ptr_eq(new, old)) {
+                    true => term.clone(),
+                    false => Rc::new(TermKind::Compound(*name, substituted)),
+                }
             },
-            TermKind::Var(n) => {
-                if let Some(t) = self.0.get(&n) {
-                    println!("Substituting {:?} for {:?}", n, t);
-                    *self.substitute(t.clone())
-                } else {
-                    TermKind::Var(n)
+            TermKind::List(_, _) => {
+                // Walk along the spine of the list rather than recursing, so that long lists don't overflow the stack
+                let mut cells = vec!();
+                let mut last = term;
+                while let TermKind::List(head, tail) = &**last {
+                    cells.push((last, self.substitute(head)));
+                    last = self.resolve(tail);
+                }
+
+                let mut list = self.substitute(last);
+                for (cell, head) in cells.into_iter().rev() {
+                    if let TermKind::List(old_head, old_tail) = &**cell {
+                        list = match Rc::ptr_eq(&head, old_head) && Rc::ptr_eq(&list, old_tail) {
+                            true => cell.clone(),
+                            false => Rc::new(TermKind::List(head, list)),
+                        };
+                    }
                 }
+                list
             },
-        })
+            _ => term.clone(),
+        }
     }
 }
 
+/// A file which has been consulted, so that it can be reloaded by `make/0` if it changes
+struct SourceFile {
+    path: String,
+    modified: Option<SystemTime>,
+    /// The predicates which have clauses in the file, which are removed before it's reloaded
+    predicates: Vec<(Atom, usize)>,
+}
+
 pub struct Database {
-    clauses: Vec<Clause>,
+    predicates: RefCell<HashMap<(Atom, usize), SharedPredicate>>,
+    generation: Cell<u64>,
+    operators: RefCell<Operators>,
+    flags: RefCell<Flags>,
+    files: RefCell<Vec<SourceFile>>,
+    /// The number of clauses each atom is used in the arguments of, kept up to date as clauses are added and removed so
+    /// that atoms can be completed at the prompt without looking through every clause
+    atoms: RefCell<BTreeMap<Atom, usize>>,
+    trace: bool,
 }
 
 impl Database {
+    /// Create a database from the given clauses, running any directives in them once the other clauses are loaded
     pub fn new(clauses: Vec<Clause>) -> Self {
-        Database {
-            clauses,
+        let db = Database {
+            predicates: RefCell::new(HashMap::new()),
+            generation: Cell::new(0),
+            operators: RefCell::new(Operators::default()),
+            flags: RefCell::new(Flags::default()),
+            files: RefCell::new(vec!()),
+            atoms: RefCell::new(BTreeMap::new()),
+            trace: false,
+        };
+        db.load(clauses);
+        db
+    }
+
+    /// Add the given clauses to the database, and then run any directives in them, returning the predicates that
+    /// had clauses.  Loading clauses for a predicate which is already defined replaces its existing clauses
+    fn load(&self, clauses: Vec<Clause>) -> Vec<(Atom, usize)> {
+        self.generation.set(self.generation.get() + 1);
+        let generation = self.generation.get();
+
+        let mut directives = vec!();
+        let mut defined = vec!();
+        let mut seen = HashSet::new();
+        let mut predicates = self.predicates.borrow_mut();
+        for clause in clauses {
+            let (head, body) = match clause {
+                Clause::Fact(head) => (head, None),
+                Clause::Rule(head, body) => (head, Some(body)),
+                Clause::Directive(goal) => { directives.push(goal); continue; },
+            };
+
+            let (name, arity) = match head.indicator() {
+                Some(indicator) => indicator,
+                None => continue,
+            };
+
+            if seen.insert((name, arity)) {
+                defined.push((name, arity));
+                let dynamic = match predicates.get(&(name, arity)).map(|predicate| predicate.borrow()) {
+                    Some(predicate) if !predicate.dynamic && !predicate.is_empty() => {
+                        println!("Warning: redefined static procedure {}/{}", name, arity);
+                        false
+                    },
+                    Some(predicate) => predicate.dynamic,
+                    None => false,
+                };
+                let predicate = Rc::new(RefCell::new(Predicate::new(dynamic, arity)));
+                if let Some(previous) = predicates.insert((name, arity), predicate) {
+                    self.count_predicate_atoms(&previous.borrow(), false);
+                }
+            }
+            if let Some(predicate) = predicates.get(&(name, arity)) {
+                let clause = Rc::new(StoredClause::new(head, body, generation));
+                self.count_atoms(&clause, true);
+                predicate.borrow_mut().push(clause);
+            }
+        }
+        drop(predicates);
+
+        for goal in directives {
+            let query = Query::new(goal.clone());
+            let mut solutions = query.solutions(self);
+            if solutions.next().is_none() {
+                match solutions.error() {
+                    Some(error) => println!("Warning: directive {} raised {}", goal, error),
+                    None => println!("Warning: directive failed: {}", goal),
+                }
+            }
+        }
+        defined
+    }
+
+    /// Load the clauses from a file into the database, replacing any clauses previously loaded from the same file.
+    /// The extensions `.plg` and `.pl` are tried if the file doesn't exist as given
+    pub fn consult(&self, filename: &str) -> Result<(), Term> {
+        let path = ["", ".plg", ".pl"].iter()
+            .map(|extension| format!("{}{}", filename, extension))
+            .find(|path| Path::new(path).is_file())
+            .ok_or_else(|| existence_error("source_sink", atom(filename)))?;
+        let path = fs::canonicalize(&path).map(|path| path.to_string_lossy().into_owned()).unwrap_or(path);
+
+        let contents = fs::read_to_string(&path).map_err(|_| permission_error("open", "source_sink", atom(filename)))?;
+        let modified = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok();
+
+        let (clauses, errors) = parse_recovering(&contents, &mut self.operators.borrow_mut());
+        for error in errors.iter() {
+            println!("Warning: {}: {}", path, error);
+        }
+        if self.trace {
+            println!("{:?}", clauses);
+        }
+
+        let previous = self.files.borrow().iter().position(|file| file.path == path);
+        if let Some(position) = previous {
+            let file = self.files.borrow_mut().remove(position);
+            let mut predicates = self.predicates.borrow_mut();
+            for key in file.predicates.iter() {
+                if let Some(previous) = predicates.remove(key) {
+                    self.count_predicate_atoms(&previous.borrow(), false);
+                }
+            }
+        }
+
+        let predicates = self.load(clauses);
+        let mut files = self.files.borrow_mut();
+        // Predicates redefined by this file now belong to it, and won't be removed when other files are reloaded
+        for file in files.iter_mut() {
+            file.predicates.retain(|key| !predicates.contains(key));
+        }
+        files.push(SourceFile { path, modified, predicates });
+        Ok(())
+    }
+
+    /// Consult again each file which has been modified since it was last loaded, returning the paths of those files
+    pub fn make(&self) -> Result<Vec<String>, Term> {
+        let changed: Vec<String> = self.files.borrow().iter()
+            .filter(|file| fs::metadata(&file.path).and_then(|metadata| metadata.modified()).ok() != file.modified)
+            .map(|file| file.path.clone())
+            .collect();
+
+        for path in changed.iter() {
+            self.consult(path)?;
+        }
+        Ok(changed)
+    }
+
+    /// Returns the given predicate, or None if it isn't defined.  Any clauses which were removed while the predicate
+    /// was in use are dropped first, if it no longer is
+    fn predicate(&self, name: Atom, arity: usize) -> Option<SharedPredicate> {
+        let predicates = self.predicates.borrow();
+        let predicate = predicates.get(&(name, arity))?;
+        if Rc::strong_count(predicate) == 1 {
+            predicate.borrow_mut().purge();
+        }
+        Some(predicate.clone())
+    }
+
+    /// Returns the clauses of the given predicate, along with whether it's dynamic, or None if it isn't defined
+    pub fn clauses(&self, name: Atom, arity: usize) -> Option<(bool, Vec<Rc<StoredClause>>)> {
+        self.predicate(name, arity).map(|predicate| {
+            let predicate = predicate.borrow();
+            (predicate.dynamic, predicate.clauses().cloned().collect())
+        })
+    }
+
+    /// Returns the name and arity of every defined predicate, sorted by name and then arity
+    pub fn predicate_indicators(&self) -> Vec<(Atom, usize)> {
+        let mut indicators: Vec<(Atom, usize)> = self.predicates.borrow().keys().cloned().collect();
+        indicators.sort();
+        indicators
+    }
+
+    /// Returns every atom used in the arguments of the clauses in the database, sorted by name
+    pub fn atoms(&self) -> Vec<Atom> {
+        self.atoms.borrow().keys().copied().collect()
+    }
+
+    /// Update the number of clauses each atom in the given clause is used in, when it's added or removed
+    fn count_atoms(&self, clause: &StoredClause, added: bool) {
+        let mut atoms = self.atoms.borrow_mut();
+        for name in clause_atoms(clause) {
+            let count = atoms.entry(name).or_insert(0);
+            match added {
+                true => *count += 1,
+                false => *count -= 1,
+            }
+            if *count == 0 {
+                atoms.remove(&name);
+            }
+        }
+    }
+
+    fn count_predicate_atoms(&self, predicate: &Predicate, added: bool) {
+        for clause in predicate.clauses() {
+            self.count_atoms(clause, added);
+        }
+    }
+
+    /// Returns each argument of the given predicate which has been indexed, numbered from 1, along with the number of
+    /// distinct keys in its index
+    pub fn indexes(&self, name: Atom, arity: usize) -> Vec<(usize, usize)> {
+        match self.predicate(name, arity) {
+            Some(predicate) => predicate.borrow().indexes().map(|(argument, index)| (argument + 1, index.len())).collect(),
+            None => vec!(),
+        }
+    }
+
+    /// Returns the current generation of the database, which increases each time a clause is added or removed
+    pub fn generation(&self) -> u64 {
+        self.generation.get()
+    }
+
+    /// Returns true if there are any clauses for the given predicate, or if it's been declared dynamic
+    pub fn is_defined(&self, name: Atom, arity: usize) -> bool {
+        self.predicates.borrow().contains_key(&(name, arity))
+    }
+
+    /// Check that the clauses of the given predicate can be changed, which is only allowed for dynamic predicates,
+    /// or predicates which haven't been defined yet
+    pub fn check_modify(&self, name: Atom, arity: usize) -> Result<(), Term> {
+        let control = arity == 2 && [",", ";", "->", "*->"].contains(&name.as_str());
+        let is_static = match self.predicates.borrow().get(&(name, arity)) {
+            Some(predicate) => !predicate.borrow().dynamic,
+            None => control || lookup_builtin(&indicator_goal(&name, arity)).is_some(),
+        };
+
+        match is_static {
+            true => Err(permission_error("modify", "static_procedure", indicator(&name, arity))),
+            false => Ok(()),
+        }
+    }
+
+    /// Declare a predicate as dynamic, so that it's defined even if it has no clauses, and can be modified
+    pub fn declare_dynamic(&self, name: Atom, arity: usize) -> Result<(), Term> {
+        // Predicates loaded from a file can be declared dynamic after their clauses, since directives are run last
+        if !self.is_defined(name, arity) {
+            self.check_modify(name, arity)?;
+        }
+
+        let mut predicates = self.predicates.borrow_mut();
+        let predicate = predicates.entry((name, arity)).or_insert_with(|| Rc::new(RefCell::new(Predicate::new(true, arity))));
+        predicate.borrow_mut().dynamic = true;
+        Ok(())
+    }
+
+    /// Add a clause to the start or end of its predicate, declaring the predicate as dynamic if it's new
+    pub fn add_clause(&self, head: Term, body: Option<Expr>, at_start: bool) -> Result<(), Term> {
+        let (name, arity) = head.indicator().ok_or_else(|| type_error("callable", head.clone()))?;
+        self.check_modify(name, arity)?;
+        let key = (name, arity);
+
+        self.generation.set(self.generation.get() + 1);
+        let clause = Rc::new(StoredClause::new(head, body, self.generation.get()));
+
+        self.count_atoms(&clause, true);
+        let mut predicates = self.predicates.borrow_mut();
+        let mut predicate = predicates.entry(key).or_insert_with(|| Rc::new(RefCell::new(Predicate::new(true, arity)))).borrow_mut();
+        match at_start {
+            true => predicate.push_front(clause),
+            false => predicate.push(clause),
         }
+        Ok(())
+    }
+
+    /// Remove the given clause, which was found at the given position in its predicate, from the database, returning
+    /// false if it had already been removed.  Calls to the predicate which are still open go on seeing the clause
+    fn remove_clause(&self, position: usize, clause: &Rc<StoredClause>) -> bool {
+        let key = match clause.head.indicator() {
+            Some(indicator) => indicator,
+            None => return false,
+        };
+
+        self.generation.set(self.generation.get() + 1);
+        let removed = match self.predicates.borrow().get(&key) {
+            // The database holds the only reference to a predicate unless there are open calls to it
+            Some(predicate) => {
+                let in_use = Rc::strong_count(predicate) > 1;
+                predicate.borrow_mut().remove(position, clause, self.generation.get(), in_use)
+            },
+            None => false,
+        };
+        if removed {
+            self.count_atoms(clause, false);
+        }
+        removed
+    }
+
+    /// Remove all clauses of a dynamic predicate, along with the predicate itself, so that it's no longer defined
+    pub fn abolish(&self, name: Atom, arity: usize) -> Result<(), Term> {
+        self.check_modify(name, arity)?;
+        if let Some(predicate) = self.predicates.borrow_mut().remove(&(name, arity)) {
+            self.count_predicate_atoms(&predicate.borrow(), false);
+        }
+        Ok(())
+    }
+
+    /// The operator table used to parse queries and programs loaded into this database
+    pub fn operators(&self) -> Ref<'_, Operators> {
+        self.operators.borrow()
+    }
+
+    pub fn operators_mut(&self) -> RefMut<'_, Operators> {
+        self.operators.borrow_mut()
+    }
+
+
+    /// The flags set by `set_prolog_flag/2`
+    pub fn flags(&self) -> Ref<'_, Flags> {
+        self.flags.borrow()
+    }
+
+    pub fn flags_mut(&self) -> RefMut<'_, Flags> {
+        self.flags.borrow_mut()
+    }
+
+    /// Enable or disable printing a trace of each step taken while solving queries
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
-pub struct Partial {
+pub struct Solution {
     pub result: Term,
     pub bindings: Bindings,
-    pub rule: usize,
+    pub answer: Answer,
 }
 
-impl Partial {
-    pub fn new(result: Term, bindings: Bindings, rule: usize) -> Partial {
-        Partial {
+impl Solution {
+    pub fn new(result: Term, bindings: Bindings, answer: Answer) -> Solution {
+        Solution {
             result,
             bindings,
-            rule,
+            answer,
         }
     }
 }
 
+/// The values of the named variables of a query in one of its solutions, in the order they first appear in the query.
+/// Variables which are still unbound are left out, so an answer with no values is printed as `true`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Answer {
+    pub values: Vec<(Atom, Term)>,
+}
+
+impl Answer {
+    fn new(variables: &[Variable], bindings: &Bindings) -> Self {
+        let values = variables.iter().filter_map(|var| {
+            let value = bindings.substitute(&Rc::new(TermKind::Var(*var)));
+            match &*value {
+                TermKind::Var(other) if other == var => None,
+                _ => Some((var.name, value)),
+            }
+        }).collect();
+        Answer { values }
+    }
+
+    /// Returns the value of the named variable, or None if it's unbound or not in the query
+    #[allow(dead_code)]
+    pub fn get(&self, name: &str) -> Option<&Term> {
+        self.values.iter().find(|(var, _)| *var == name).map(|(_, value)| value)
+    }
+}
+
+impl fmt::Display for Answer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.values.is_empty() {
+            return write!(f, "true");
+        }
+
+        for (i, (name, value)) in self.values.iter().enumerate() {
+            if i > 0 {
+                writeln!(f, ",")?;
+            }
+            write!(f, "{} = {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
 pub struct Query {
     goal: Term,
+    /// The named variables of the query which are reported in each answer, in the order they first appear
+    variables: Vec<Variable>,
 }
 
 impl Query {
     pub fn new(term: Term) -> Self {
+        let mut variables = vec!();
+        map_variables(&term, &mut |var| {
+            if !var.name.starts_with('_') && !variables.contains(&var) {
+                variables.push(var);
+            }
+            Rc::new(TermKind::Var(var))
+        });
+
         Query {
             goal: term,
+            variables,
+        }
+    }
+
+    /// Returns an iterator which lazily produces each solution to the query, in the order Prolog would find them.  The
+    /// query is interpreted the same way as the body of a clause, so it can contain any of the control constructs
+    pub fn solutions<'db>(&self, db: &'db Database) -> Solutions<'db> {
+        Solutions {
+            goal: self.goal.clone(),
+            variables: self.variables.clone(),
+            machine: Machine::new(db, self.goal.clone().into()),
+            started: false,
         }
     }
 
     #[allow(dead_code)]
-    pub fn solve(&self, db: &Database) -> Option<Partial> {
-        self.solve_from(db, 0)
+    pub fn solve(&self, db: &Database) -> Option<Solution> {
+        self.solutions(db).next()
+    }
+}
+
+pub struct Solutions<'db> {
+    goal: Term,
+    variables: Vec<Variable>,
+    machine: Machine<'db>,
+    started: bool,
+}
+
+impl<'db> Solutions<'db> {
+    /// Returns the uncaught error which ended the search for solutions, if any
+    pub fn error(&self) -> Option<&Term> {
+        self.machine.error()
+    }
+
+    /// Returns true if there are choice points left which might produce more solutions.  The choice points of catch/3
+    /// and those which have been cut can't produce a solution, and neither can a branch which is just `fail`
+    pub fn has_alternatives(&self) -> bool {
+        self.machine.choices.iter().any(|choice| match &choice.alternative {
+            Alternative::Clauses(_, _, _, _, _) => true,
+            Alternative::Expr(expr, _) => match &**expr {
+                ExprKind::Term(goal) => !matches!(&**goal, TermKind::Atom(name) if *name == FAIL),
+                _ => true,
+            },
+            Alternative::Disabled | Alternative::Catch(_, _, _) => false,
+        })
+    }
+}
+
+impl<'db> Iterator for Solutions<'db> {
+    type Item = Solution;
+
+    fn next(&mut self) -> Option<Solution> {
+        let found = if !self.started {
+            self.started = true;
+            self.machine.run()
+        } else {
+            // Resume from the most recent choice point to look for the next solution
+            self.machine.backtrack() && self.machine.run()
+        };
+
+        match found {
+            true => {
+                let bindings = &self.machine.bindings;
+                Some(Solution::new(bindings.substitute(&self.goal), bindings.clone(), Answer::new(&self.variables, bindings)))
+            },
+            false => None,
+        }
     }
+}
+
+/// The goals remaining to be solved, stored as a linked list of frames so that choice points can share them
+#[derive(Clone, Default)]
+struct Goals(Option<Rc<GoalFrame>>);
 
-    pub fn solve_from(&self, db: &Database, at: usize) -> Option<Partial> {
-        if at >= db.clauses.len() {
-            return None;
+/// A goal to be solved, along with the height of the choice point stack to cut back to if the goal is a cut
+struct GoalFrame {
+    expr: Expr,
+    cut_barrier: usize,
+    next: Goals,
+}
+
+impl Goals {
+    fn push(&mut self, expr: Expr, cut_barrier: usize) {
+        let next = Goals(self.0.take());
+        self.0 = Some(Rc::new(GoalFrame { expr, cut_barrier, next }));
+    }
+
+    /// Push each goal of a conjunction separately, so that the last goal of a clause body replaces its caller's
+    /// frame instead of nesting inside of it
+    fn push_expr(&mut self, expr: Expr, cut_barrier: usize) {
+        let mut conjuncts = vec!();
+        let mut last = expr;
+        while matches!(*last, ExprKind::Conjunct(_, _)) {
+            if let ExprKind::Conjunct(expr1, expr2) = *last {
+                conjuncts.push(expr1);
+                last = expr2;
+            }
         }
 
-        println!("Solving {:?} at {}", self.goal, at);
-        if let Some(func) = lookup_builtin(&self.goal) {
-            return func(db, &self.goal, &Bindings::empty(), db.clauses.len());
+        self.push(last, cut_barrier);
+        for expr in conjuncts.into_iter().rev() {
+            self.push(expr, cut_barrier);
+        }
+    }
+}
+
+impl Drop for Goals {
+    fn drop(&mut self) {
+        // Free unshared frames one at a time, so that dropping a deep continuation doesn't overflow the stack
+        let mut next = self.0.take();
+        while let Some(frame) = next {
+            next = match Rc::try_unwrap(frame) {
+                Ok(mut frame) => frame.next.0.take(),
+                Err(_) => None,
+            };
         }
+    }
+}
 
-        for i in at..db.clauses.len() {
-            match &db.clauses[i] {
-                Clause::Fact(t) => {
-                    let iteration = UniqueID::generate();
-                    let t = rename_term(t.clone(), iteration);
+/// A point to resume from when backtracking, which records the state of the machine at the time it was created
+struct ChoicePoint {
+    goals: Goals,
+    trail: usize,
+    /// The lowest variable id that wasn't in use yet when the choice point was created
+    boundary: usize,
+    alternative: Alternative,
+}
 
-                    println!("Unifying fact {} with {}", self.goal, t);
-                    if let Some((n, bindings)) = unify_term(&self.goal, &t) {
-                        println!("Returning {:?} {:?}", n, bindings);
-                        return Some(Partial::new(bindings.substitute(n), bindings, i));
-                    }
-                },
-                Clause::Rule(lhs, rhs) => {
-                    let iteration = UniqueID::generate();
-                    let lhs = rename_term(lhs.clone(), iteration);
-                    let rhs = rename_expr(rhs.clone(), iteration);
-
-                    println!("Unifying rule {} with {}", self.goal, lhs);
-                    if let Some((_, mut bindings)) = unify_term(&self.goal, &lhs) {
-                        if let Some(partial) = self._solve_expression(db, &bindings, &rhs, 0) {
-                            bindings = bindings.merge(&partial.bindings)?;
-                            println!("Returning {:?} {:?}", bindings.substitute(lhs.clone()), bindings);
-                            return Some(Partial::new(bindings.substitute(lhs.clone()), bindings, i));
-                        }
-                    }
+enum Alternative {
+    /// The goal of a predicate call, the predicate, the argument whose index was used to select the candidate clauses,
+    /// if any, the place in the candidates of the next one which might match the goal, and the generation of the
+    /// database when the call was made, which decides which clauses it sees
+    Clauses(Term, SharedPredicate, Option<usize>, isize, u64),
+    /// An expression to try instead, such as the right hand side of a disjunction, along with its cut barrier
+    Expr(Expr, usize),
+    /// The else branch of a soft-cut whose condition has succeeded, which will never be tried
+    Disabled,
+    /// A call to catch/3, with its catcher and recovery goal, and the frame which marks the end of its goal.  It's only
+    /// active while that frame is still in the goal stack, and has no alternative to try when backtracking
+    Catch(Term, Term, Rc<GoalFrame>),
+}
+
+pub struct Machine<'db> {
+    pub db: &'db Database,
+    pub bindings: Bindings,
+    goals: Goals,
+    choices: Vec<ChoicePoint>,
+    cut_barrier: usize,
+    exception: Option<Term>,
+    error: Option<Term>,
+    /// The offset to add to the ids of a clause's variables the next time one is renamed
+    next_id: usize,
+    /// The query, whose variables must always be kept, even once it's no longer in the goal stack
+    query: Term,
+    /// The number of bindings at which the bindings that can't be reached anymore are next removed
+    gc_threshold: usize,
+}
+
+/// The fewest bindings there can be before unreachable bindings are removed
+const MIN_GC_THRESHOLD: usize = 65536;
+
+impl<'db> Machine<'db> {
+    pub fn new(db: &'db Database, goal: Expr) -> Self {
+        let query = Term::from(goal.clone());
+        let mut goals = Goals::default();
+        goals.push_expr(goal, 0);
+
+        Machine {
+            db,
+            bindings: Bindings::empty(),
+            goals,
+            choices: vec!(),
+            cut_barrier: 0,
+            exception: None,
+            error: None,
+            next_id: 0,
+            query,
+            gc_threshold: MIN_GC_THRESHOLD,
+        }
+    }
+
+    pub fn trace(&self, args: fmt::Arguments) {
+        if self.db.trace {
+            println!("{}", args);
+        }
+    }
+
+    /// Push a goal to be solved next.  A cut inside the goal will only remove choice points created by the goal itself
+    pub fn push_goal(&mut self, expr: Expr) {
+        self.goals.push_expr(expr, self.choices.len());
+    }
+
+    /// Remove all choice points created since the predicate containing the current goal was called
+    pub fn cut(&mut self) {
+        self.trace(format_args!("Cut"));
+        self.choices.truncate(self.cut_barrier);
+        self.update_boundary();
+    }
+
+    /// Disable the else branch of the soft-cut whose choice point is at the current cut barrier, without removing any
+    /// choice points created by its condition
+    pub fn soft_cut(&mut self) {
+        if let Some(choice) = self.choices.get_mut(self.cut_barrier) {
+            choice.alternative = Alternative::Disabled;
+        }
+    }
+
+    /// Raise an exception, which is handled once the current goal returns by unwinding to the most recent active
+    /// catch/3 whose catcher unifies with it
+    pub fn throw(&mut self, ball: Term) {
+        self.trace(format_args!("Throwing {}", ball));
+        self.exception = Some(self.bindings.substitute(&ball));
+    }
+
+    /// Call the goal, catching any exception it raises which unifies with the catcher, and calling the recovery goal
+    /// instead.  Like call/1, a cut inside the goal is local to it
+    pub fn push_catch(&mut self, goal: Term, catcher: Term, recovery: Term) {
+        let goals = self.goals.clone();
+        let index = self.choices.len();
+        self.goals.push(Term::from(EXIT_CATCH).into(), index);
+        let marker = self.goals.0.clone().unwrap();
+
+        self.push_choice(goals, self.bindings.mark(), self.next_id + 1, Alternative::Catch(catcher, recovery, marker));
+        self.push_goal(goal.into());
+    }
+
+    /// Called when the goal of a catch/3 succeeds.  If the goal left no choice points, then the catch can never
+    /// become active again, so its choice point is removed
+    pub fn exit_catch(&mut self) {
+        if self.choices.len() == self.cut_barrier + 1 {
+            self.choices.pop();
+            self.update_boundary();
+        }
+    }
+
+    /// Restore the state of the most recent active catch/3 whose catcher unifies with the exception, and continue with
+    /// its recovery goal, or stop the machine with an error if it isn't caught by anything
+    fn unwind(&mut self, ball: Term) -> bool {
+        // A catch is only active if the marker for the end of its goal hasn't been reached yet
+        let mut active = vec!();
+        let mut next = self.goals.0.as_ref();
+        while let Some(frame) = next {
+            active.push(Rc::as_ptr(frame));
+            next = frame.next.0.as_ref();
+        }
+
+        while let Some(choice) = self.choices.pop() {
+            if let Alternative::Catch(catcher, recovery, marker) = choice.alternative {
+                if !active.contains(&Rc::as_ptr(&marker)) {
+                    continue;
+                }
+
+                self.update_boundary();
+                self.bindings.undo_to(choice.trail);
+                if unify_term(&catcher, &ball, &mut self.bindings) {
+                    self.trace(format_args!("Caught {}", ball));
+                    self.goals = choice.goals;
+                    self.push_goal(recovery.into());
+                    return true;
+                }
+            }
+        }
+
+        self.trace(format_args!("Uncaught exception {}", ball));
+        self.update_boundary();
+        self.goals = Goals::default();
+        self.error = Some(ball);
+        false
+    }
+
+    /// Returns the error that stopped the machine, if any
+    pub fn error(&self) -> Option<&Term> {
+        self.error.as_ref()
+    }
+
+    fn push_alternative(&mut self, alternative: Alternative) {
+        self.push_choice(self.goals.clone(), self.bindings.mark(), self.next_id + 1, alternative);
+    }
+
+    fn push_choice(&mut self, goals: Goals, trail: usize, boundary: usize, alternative: Alternative) {
+        self.choices.push(ChoicePoint { goals, trail, boundary, alternative });
+        self.update_boundary();
+    }
+
+    /// Trail the bindings of the variables which existed when the most recent choice point was created, after the
+    /// choice points have changed
+    fn update_boundary(&mut self) {
+        self.bindings.boundary = self.choices.last().map(|choice| choice.boundary).unwrap_or(0);
+    }
+
+    /// Remove the bindings which can't be reached from the query, the remaining goals, or any of the choice points,
+    /// which would otherwise grow with every step of a long running deterministic loop
+    fn collect_garbage(&mut self) {
+        let mut roots = vec!(self.query.clone());
+        let mut frames = HashSet::new();
+        let mut collect_goals = |goals: &Goals, roots: &mut Vec<Term>| {
+            let mut next = goals.0.as_ref();
+            // Choice points share the frames of the goal stack, so each frame only needs to be visited once
+            while let Some(frame) = next.filter(|frame| frames.insert(Rc::as_ptr(frame))) {
+                expr_terms(&frame.expr, roots);
+                next = frame.next.0.as_ref();
+            }
+        };
+
+        collect_goals(&self.goals, &mut roots);
+        for choice in self.choices.iter() {
+            collect_goals(&choice.goals, &mut roots);
+            match &choice.alternative {
+                Alternative::Clauses(goal, _, _, _, _) => roots.push(goal.clone()),
+                Alternative::Expr(expr, _) => expr_terms(expr, &mut roots),
+                Alternative::Catch(catcher, recovery, marker) => {
+                    roots.push(catcher.clone());
+                    roots.push(recovery.clone());
+                    expr_terms(&marker.expr, &mut roots);
                 },
+                Alternative::Disabled => { },
             }
         }
 
-        None
+        let before = self.bindings.len();
+        self.bindings.retain_reachable(roots);
+        self.trace(format_args!("Removed {} unreachable bindings", before - self.bindings.len()));
+        self.gc_threshold = MIN_GC_THRESHOLD.max(self.bindings.len() * 2);
+    }
+
+    /// Run until all goals have been solved (returning true) or there are no more choice points left (returning false)
+    pub fn run(&mut self) -> bool {
+        while let Some(frame) = self.goals.0.clone() {
+            self.goals = frame.next.clone();
+            self.cut_barrier = frame.cut_barrier;
+            let success = self.step(&frame.expr);
+
+            if let Some(ball) = self.exception.take() {
+                if !self.unwind(ball) {
+                    return false;
+                }
+            } else if !success && !self.backtrack() {
+                self.trace(format_args!("Out of backtrack options"));
+                return false;
+            }
+
+            if self.bindings.len() >= self.gc_threshold {
+                self.collect_garbage();
+            }
+        }
+        true
     }
 
-    fn _solve_expression(&self, db: &Database, init_bindings: &Bindings, expr: &Expr, at_rule: usize) -> Option<Partial> {
+    fn step(&mut self, expr: &Expr) -> bool {
         match &**expr {
             ExprKind::Term(term) => {
-                let dependent = Query { goal: init_bindings.substitute(term.clone()) };
-                match dependent.solve_from(db, at_rule) {
-                    Some(mut partial) => {
-                        partial.bindings = init_bindings.merge(&partial.bindings)?;
-                        Some(partial)
+                let is_variable = matches!(**term, TermKind::Var(_));
+                let term = self.bindings.resolve(term).clone();
+                self.trace(format_args!("Solving {}", term));
+
+                // A variable used as a goal is called as if it were the argument to call/1
+                if is_variable {
+                    match *term {
+                        TermKind::Var(_) => self.throw(error(instantiation_error(), "call", 1)),
+                        TermKind::Atom(_) | TermKind::Compound(_, _) => self.push_goal(term.into()),
+                        _ => self.throw(error(type_error("callable", term), "call", 1)),
+                    }
+                    return true;
+                }
+
+                if let Some(func) = lookup_builtin(&term) {
+                    let goal = self.bindings.substitute(&term);
+                    return func(self, &goal).is_some();
+                }
+
+                let predicate = match term.indicator().and_then(|(name, arity)| self.db.predicate(name, arity)) {
+                    Some(predicate) => predicate,
+                    None => return self.undefined_procedure(&term),
+                };
+
+                // Only the clauses selected by the most selective index on the goal's bound arguments are tried, and only
+                // those which are in the database now
+                let generation = self.db.generation();
+                let clauses = predicate.borrow();
+                let keys = goal_keys(&term, &clauses, &self.bindings);
+                let (argument, candidates) = clauses.select(&keys);
+                let start = candidates.places().start;
+                let (first, clause) = match next_candidate(&clauses, candidates, &term, &self.bindings, start, generation) {
+                    Some(first) => first,
+                    None => return false,
+                };
+
+                // A cut in the body of the clause will remove this call's choice point and any created after it
+                let cut_barrier = self.choices.len();
+                match next_candidate(&clauses, candidates, &term, &self.bindings, first + 1, generation) {
+                    // If only one clause could match, then no choice point is needed
+                    None => self.try_clause(&term, clause, cut_barrier),
+                    Some(_) => {
+                        self.push_alternative(Alternative::Clauses(term, predicate.clone(), argument, first, generation));
+                        self.resume()
                     },
-                    None => None,
                 }
             },
-            ExprKind::Conjunct(expr1, expr2) => {
-                let mut rule = 0;
-                loop {
-                    match self._solve_expression(db, init_bindings, expr1, rule) {
-                        Some(partial) => {
-                            // The first expr has a result, and if the second expr also has a result, then return
-                            // Otherwise backtrack and try to find another solution to the first expr
-                            let bindings = init_bindings.merge(&partial.bindings)?;
-                            if let Some(partial) = self._solve_expression(db, &bindings, expr2, 0) {
-                                return Some(partial);
-                            }
-
-                            // If the previous expression was the cut operator, then don't backtrack
-                            if is_atom_of(expr1, "!") {
-                                println!("Cut");
-                                return None;
-                            }
-
-                            rule = partial.rule + 1;
-                            println!("Backtracking");
-                        },
-                        None => {
-                            println!("Out of backtrack options");
-                            return None;
-                       }
-                    }
-                }
+            ExprKind::Conjunct(_, _) => {
+                self.goals.push_expr(expr.clone(), self.cut_barrier);
+                true
+            },
+            ExprKind::Disjunct(expr1, expr2) => {
+                // A cut inside either branch of a disjunction cuts the clause the disjunction is in
+                self.push_alternative(Alternative::Expr(expr2.clone(), self.cut_barrier));
+                self.goals.push_expr(expr1.clone(), self.cut_barrier);
+                true
+            },
+            ExprKind::IfThenElse(cond, then, otherwise) => {
+                // Once the condition succeeds, cut back to before the else branch, which also removes any choice points
+                // created by the condition.  A cut inside the condition itself is local to the condition
+                let else_barrier = self.choices.len();
+                let otherwise = otherwise.clone().unwrap_or_else(|| Term::from(FAIL).into());
+                self.push_alternative(Alternative::Expr(otherwise, self.cut_barrier));
+                self.goals.push_expr(then.clone(), self.cut_barrier);
+                self.goals.push(Term::from(CUT).into(), else_barrier);
+                self.goals.push_expr(cond.clone(), self.choices.len());
+                true
+            },
+            ExprKind::SoftCut(cond, then, otherwise) => {
+                // Once the condition succeeds, only the else branch is removed, so the condition can still backtrack
+                let else_barrier = self.choices.len();
+                let otherwise = otherwise.clone().unwrap_or_else(|| Term::from(FAIL).into());
+                self.push_alternative(Alternative::Expr(otherwise, self.cut_barrier));
+                self.goals.push_expr(then.clone(), self.cut_barrier);
+                self.goals.push(Term::from(SOFT_CUT).into(), else_barrier);
+                self.goals.push_expr(cond.clone(), self.choices.len());
+                true
             },
         }
     }
-}
 
+    /// Called when a goal's predicate isn't defined at all, which does what the `unknown` flag says
+    fn undefined_procedure(&mut self, goal: &Term) -> bool {
+        let (name, arity) = match goal.indicator() {
+            Some(indicator) => indicator,
+            None => {
+                self.throw(error(type_error("callable", goal.clone()), "call", 1));
+                return true;
+            },
+        };
 
-pub fn is_atom_of(expr: &Expr, expected: &str) -> bool {
-    if let ExprKind::Term(term) = &**expr {
-        match &**term {
-            TermKind::Atom(string) | TermKind::Compound(string, _) => {
-                if string.as_str() == expected {
-                    return true;
-                }
+        let unknown = self.db.flags().unknown;
+        match unknown {
+            Unknown::Error => {
+                self.throw(error(existence_error("procedure", indicator(&name, arity)), &name, arity));
+                true
+            },
+            Unknown::Warning => {
+                println!("Warning: unknown procedure {}/{}", name, arity);
+                false
             },
-            _ => { },
+            Unknown::Fail => false,
         }
     }
-    false
-}
 
-pub fn unify_term(term1: &Term, term2: &Term) -> Option<(Term, Bindings)> {
-    //println!("Unifying term {:?} and {:?}", term1, term2);
-    match (&**term1, &**term2) {
-        (TermKind::Atom(n), t) |
-        (t, TermKind::Atom(n)) if n == "_" => Some((Box::new(t.clone()), Bindings::empty())),
+    /// Restore the state of the most recent choice point and try its next alternative
+    pub fn backtrack(&mut self) -> bool {
+        while !self.choices.is_empty() {
+            self.trace(format_args!("Backtracking"));
+            if self.resume() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Restore the state of the most recent choice point and try its alternative, removing it if there are no other
+    /// alternatives left to try
+    fn resume(&mut self) -> bool {
+        let choice = match self.choices.pop() {
+            Some(choice) => choice,
+            None => return false,
+        };
+        self.update_boundary();
 
-        (TermKind::Atom(n), TermKind::Atom(m)) if n == m => Some((Box::new(TermKind::Atom(n.clone())), Bindings::empty())),
+        match choice.alternative {
+            Alternative::Clauses(goal, predicate, argument, next_clause, generation) => {
+                self.resume_clauses(choice.goals, choice.trail, choice.boundary, goal, predicate, argument, next_clause, generation)
+            },
+            Alternative::Expr(expr, cut_barrier) => {
+                self.bindings.undo_to(choice.trail);
+                self.goals = choice.goals;
+                self.goals.push_expr(expr, cut_barrier);
+                true
+            },
+            Alternative::Disabled | Alternative::Catch(_, _, _) => false,
+        }
+    }
 
-        (TermKind::Integer(n), TermKind::Integer(m)) if n == m => Some((Box::new(TermKind::Integer(*n)), Bindings::empty())),
+    /// Try the remaining clauses of a predicate call, starting from the given clause.  If the clause that matches is
+    /// the last candidate, then the choice point is not restored before continuing, so that deterministic predicates
+    /// don't accumulate choice points, and tail calls run in constant space
+    #[allow(clippy::too_many_arguments)]
+    fn resume_clauses(&mut self, goals: Goals, trail: usize, boundary: usize, goal: Term, predicate: SharedPredicate, argument: Option<usize>, next_clause: isize, generation: u64) -> bool {
+        self.bindings.undo_to(trail);
+        let clauses = predicate.borrow();
+        let key = argument.and_then(|argument| goal_key(&goal, argument, &self.bindings));
+        let candidates = clauses.candidates(argument, key.as_ref());
 
-        (TermKind::String(n), TermKind::String(m)) if n == m => Some((Box::new(TermKind::String(n.clone())), Bindings::empty())),
+        // The next clause can't have been dropped from the predicate, since this call has been using it
+        let mut candidate = candidates.get(next_clause).and_then(|position| clauses.get(position)).map(|clause| (next_clause, clause));
+        while let Some((place, clause)) = candidate {
+            self.bindings.undo_to(trail);
+            self.goals = goals.clone();
 
-        (TermKind::Compound(n, args1), TermKind::Compound(m, args2)) if n == m && args1.len() == args2.len() => {
-            let mut args = vec!();
-            let mut bindings = Bindings::empty();
-
-            for (a1, a2) in args1.iter().zip(args2.iter()) {
-                if let Some((n, new_bindings)) = unify_term(a1, a2) {
-                    args.push(n);
-                    bindings = bindings.merge(&new_bindings)?;
-                } else {
-                    return None;
+            candidate = next_candidate(&clauses, candidates, &goal, &self.bindings, place + 1, generation);
+            // The bindings made by the clause must be trailed if the choice point will be restored afterwards
+            if candidate.is_some() {
+                self.bindings.boundary = boundary;
+            }
+            if self.try_clause(&goal, clause, self.choices.len()) {
+                match candidate {
+                    Some((next_clause, _)) => {
+                        let alternative = Alternative::Clauses(goal, predicate.clone(), argument, next_clause, generation);
+                        self.push_choice(goals, trail, boundary, alternative);
+                    },
+                    None => self.update_boundary(),
                 }
+                return true;
             }
-            Some((Box::new(TermKind::Compound(n.clone(), args)), bindings))
-        },
+        }
 
-        (TermKind::EmptyList, TermKind::EmptyList) => Some((Box::new(TermKind::EmptyList), Bindings::empty())),
+        self.update_boundary();
+        false
+    }
 
-        (TermKind::List(h1, t1), TermKind::List(h2, t2)) => {
-            let (head, head_bindings) = unify_term(h1, h2)?;
-            let (tail, tail_bindings) = unify_term(t1, t2)?;
-            Some((Box::new(TermKind::List(head, tail)), head_bindings.merge(&tail_bindings)?))
-        },
+    /// Unify the goal with the head of the given clause and if successful, push the clause's body onto the goal stack
+    fn try_clause(&mut self, goal: &Term, clause: &StoredClause, cut_barrier: usize) -> bool {
+        let (head, body) = self.rename_clause(clause);
 
-        (TermKind::Var(n), TermKind::Var(m)) if n == m => {
-            Some((Box::new(TermKind::Var(n.clone())), Bindings::empty()))
-        },
+        self.trace(format_args!("Unifying {} with {}", goal, head));
+        if !unify_term(goal, &head, &mut self.bindings) {
+            return false;
+        }
 
-        (TermKind::Var(n), m) | (m, TermKind::Var(n)) => {
-            println!("Binding {} to {}", n, m);
-            Some((Box::new(m.clone()), Bindings::with(n, Box::new(m.clone()))))
-        },
+        if let Some(body) = body {
+            self.goals.push_expr(body, cut_barrier);
+        }
+        true
+    }
+
+    /// Remove the first clause added before the given generation which unifies with `head :- body`, leaving a choice
+    /// point to retract the next matching clause when backtracking, if there are any others
+    pub fn retract(&mut self, head: &Term, body: &Term, generation: u64) -> bool {
+        let predicate = match head.indicator().and_then(|(name, arity)| self.db.predicate(name, arity)) {
+            Some(predicate) => predicate,
+            None => return false,
+        };
 
-        _ => None
+        // Only the clauses selected by the most selective index on the head's bound arguments are considered, as for a
+        // call, and only if they existed when the retract was first called and haven't been removed since
+        let clauses = predicate.borrow();
+        let keys = goal_keys(head, &clauses, &self.bindings);
+        let (_, candidates) = clauses.select(&keys);
+        let is_candidate = |clause: &StoredClause, bindings: &Bindings| {
+            clause.generation <= generation && clause.erased.get().is_none() && might_unify(head, &clause.head, bindings)
+        };
+
+        let term = compound(":-", vec!(head.clone(), body.clone()));
+        let mut found = None;
+        for (i, &position) in candidates.iter().enumerate() {
+            let clause = match clauses.get(position) {
+                Some(clause) if is_candidate(clause, &self.bindings) => clause,
+                _ => continue,
+            };
+
+            let clause_term = self.clause_term(clause);
+            if unify_by_reference(&term, &clause_term, &self.bindings).is_some() {
+                found = Some((i, position, clause.clone(), clause_term));
+                break;
+            }
+        }
+
+        let (i, position, clause, clause_term) = match found {
+            Some(found) => found,
+            None => return false,
+        };
+        let retry = candidates.iter().skip(i + 1).any(|&position| match clauses.get(position) {
+            Some(clause) => is_candidate(clause, &self.bindings),
+            None => false,
+        });
+
+        // The predicate is let go of first, so that the clause can be dropped straight away if no calls are using it
+        drop(clauses);
+        drop(predicate);
+        self.db.remove_clause(position, &clause);
+
+        // If there might be other clauses to retract, then the bindings are trailed so that they're undone first
+        let boundary = self.next_id + 1;
+        let trail = self.bindings.mark();
+        if retry {
+            self.bindings.boundary = boundary;
+        }
+        unify_term(&term, &clause_term, &mut self.bindings);
+
+        if retry {
+            let retry = compound("$retract", vec!(head.clone(), body.clone(), integer(generation as i64)));
+            self.push_choice(self.goals.clone(), trail, boundary, Alternative::Expr(retry.into(), self.cut_barrier));
+        }
+        true
+    }
+
+    /// Remove every clause whose head unifies with the given term, without binding any variables
+    pub fn retract_all(&mut self, head: &Term) {
+        let predicate = match head.indicator().and_then(|(name, arity)| self.db.predicate(name, arity)) {
+            Some(predicate) => predicate,
+            None => return,
+        };
+
+        let clauses = predicate.borrow();
+        let keys = goal_keys(head, &clauses, &self.bindings);
+        let (_, candidates) = clauses.select(&keys);
+        let mut matching = vec!();
+        for &position in candidates.iter() {
+            if let Some(clause) = clauses.get(position).filter(|clause| clause.erased.get().is_none()) {
+                let (clause_head, _) = self.rename_clause(clause);
+                if unify_by_reference(head, &clause_head, &self.bindings).is_some() {
+                    matching.push((position, clause.clone()));
+                }
+            }
+        }
+
+        drop(clauses);
+        drop(predicate);
+        for (position, clause) in matching {
+            self.db.remove_clause(position, &clause);
+        }
+    }
+
+    /// Returns a renamed copy of a clause as the term `Head :- Body`, where a fact has a body of `true`
+    fn clause_term(&mut self, clause: &StoredClause) -> Term {
+        let (clause_head, clause_body) = self.rename_clause(clause);
+        let clause_body = clause_body.map(Term::from).unwrap_or_else(|| TRUE.into());
+        compound(":-", vec!(clause_head, clause_body))
+    }
+
+    /// Copy a clause with fresh variables, which are distinct from the variables of any other use of the clause in
+    /// this session.  The ids of the clause's variables are numbered from 1, so they're offset by the next free id
+    fn rename_clause(&mut self, clause: &StoredClause) -> (Term, Option<Expr>) {
+        let offset = self.next_id;
+        self.next_id += clause.variables;
+
+        let mut rename = |var: Variable| Rc::new(TermKind::Var(Variable { name: var.name, id: var.id + offset }));
+        let head = map_variables(&clause.head, &mut rename);
+        let body = clause.body.as_ref().map(|body| map_expr_variables(body, &mut rename));
+        (head, body)
     }
 }
 
-pub fn simplify_term(db: &Database, term: &Term, bindings: &Bindings, at_rule: usize) -> Option<Partial> {
-    if let Some(func) = lookup_builtin(term) {
-        match func(db, term, bindings, at_rule) {
-            Some(partial) => simplify_term(db, &partial.result, &partial.bindings, partial.rule),
-            None => None,
+/// Returns the atoms used in the arguments of a clause, including inside lists, but not the names of compound terms
+fn clause_atoms(clause: &StoredClause) -> HashSet<Atom> {
+    let mut atoms = HashSet::new();
+    let mut pending: Vec<Term> = vec!(clause.head.clone());
+    if let Some(body) = &clause.body {
+        pending.push(body.clone().into());
+    }
+
+    while let Some(term) = pending.pop() {
+        let args = match &*term {
+            TermKind::Compound(_, args) => args.clone(),
+            TermKind::List(head, tail) => vec!(head.clone(), tail.clone()),
+            _ => continue,
+        };
+
+        for arg in args {
+            match &*arg {
+                TermKind::Atom(name) => { atoms.insert(*name); },
+                _ => pending.push(arg),
+            }
         }
-    } else {
-        Some(Partial::new(term.clone(), bindings.clone(), at_rule))
     }
+    atoms
+}
+
+/// Find the next of the candidate clauses, starting from the given place, which was in the database in the given
+/// generation and whose head might unify with the goal, along with the clause itself
+fn next_candidate<'p>(predicate: &'p Predicate, candidates: &ClauseList, goal: &Term, bindings: &Bindings, from: isize, generation: u64) -> Option<(isize, &'p Rc<StoredClause>)> {
+    (from..candidates.places().end)
+        .filter_map(|place| candidates.get(place).and_then(|position| predicate.get(position)).map(|clause| (place, clause)))
+        .find(|(_, clause)| clause.is_visible(generation) && might_unify(goal, &clause.head, bindings))
 }
 
-pub fn compare_term(term1: &Term, term2: &Term) -> bool {
-    match (&**term1, &**term2) {
-        (TermKind::Atom(n), TermKind::Atom(m)) if n == m => true,
-        (TermKind::Integer(n), TermKind::Integer(m)) if n == m => true,
-        (TermKind::String(n), TermKind::String(m)) if n == m => true,
+/// Returns the key of one of the goal's arguments, with any bindings applied, if it has one
+fn goal_key(goal: &Term, argument: usize, bindings: &Bindings) -> Option<Key> {
+    match &**goal {
+        TermKind::Compound(_, args) => Key::of(bindings.resolve(&args[argument])),
+        _ => None,
+    }
+}
+
+/// Returns the keys of each of the goal's arguments which can be indexed in the given predicate
+fn goal_keys(goal: &Term, predicate: &Predicate, bindings: &Bindings) -> Vec<Option<Key>> {
+    match &**goal {
+        TermKind::Compound(_, args) => (0..args.len())
+            .map(|argument| match predicate.is_indexable(argument) {
+                true => goal_key(goal, argument, bindings),
+                false => None,
+            })
+            .collect(),
+        _ => vec!(),
+    }
+}
+
+/// Returns a goal with the given name and arity, whose arguments are all variables
+fn indicator_goal(name: &str, arity: usize) -> Term {
+    match arity {
+        0 => atom(name),
+        _ => compound(name, (0..arity).map(|i| variable(&format!("_{}", i))).collect()),
+    }
+}
+
+/// A quick check of the functors and first arguments of two terms, which is false only if they definitely won't unify
+fn might_unify(goal: &Term, head: &Term, bindings: &Bindings) -> bool {
+    match (&**goal, &**head) {
+        (TermKind::Atom(n), TermKind::Atom(m)) => n == m,
         (TermKind::Compound(n, args1), TermKind::Compound(m, args2)) if n == m && args1.len() == args2.len() => {
-            for (a1, a2) in args1.iter().zip(args2.iter()) {
-                if !compare_term(a1, a2) {
-                    return false;
-                }
+            match (&**bindings.resolve(&args1[0]), &*args2[0]) {
+                (TermKind::Var(_), _) | (_, TermKind::Var(_)) => true,
+                (TermKind::Atom(n), _) | (_, TermKind::Atom(n)) if n == "_" => true,
+                (TermKind::Atom(n), TermKind::Atom(m)) => n == m,
+                (TermKind::Integer(n), TermKind::Integer(m)) => n == m,
+                (TermKind::BigInteger(n), TermKind::BigInteger(m)) => n == m,
+                (TermKind::Float(n), TermKind::Float(m)) => n == m,
+                (TermKind::String(n), TermKind::String(m)) => n == m,
+                (TermKind::Compound(n, args1), TermKind::Compound(m, args2)) => n == m && args1.len() == args2.len(),
+                (TermKind::EmptyList, TermKind::EmptyList) => true,
+                (TermKind::List(_, _), TermKind::List(_, _)) => true,
+                _ => false,
             }
-            true
         },
-        (TermKind::EmptyList, TermKind::EmptyList) => true,
-        (TermKind::List(h1, t1), TermKind::List(h2, t2)) => {
-            if !compare_term(h1, h2) {
-                return false;
-            }
-            compare_term(t1, t2)
-        },
-        (TermKind::Var(n), TermKind::Var(m)) if n == m => true,
         _ => false,
     }
 }
 
-fn rename_term(term: Term, iteration: UniqueID) -> Term {
-    Box::new(match *term {
-        TermKind::EmptyList => TermKind::EmptyList,
-        TermKind::Atom(n) => TermKind::Atom(n),
-        TermKind::Integer(n) => TermKind::Integer(n),
-        TermKind::String(n) => TermKind::String(n),
-        TermKind::Compound(n, args) => {
-            let args = args.iter().map(|t| rename_term(t.clone(), iteration)).collect();
-            TermKind::Compound(n, args)
-        },
-        TermKind::List(head, tail) => {
-            TermKind::List(rename_term(head, iteration), rename_term(tail, iteration))
-        },
-        TermKind::Var(n) => {
-            TermKind::Var(format!("{}_{}", n, iteration))
-        },
-    })
+
+pub fn unify_term(term1: &Term, term2: &Term, bindings: &mut Bindings) -> bool {
+    let pending: Vec<(Variable, Term)> = match unify_by_reference(term1, term2, bindings) {
+        Some(pending) => pending.into_iter().map(|(var, term)| (var, term.clone())).collect(),
+        None => return false,
+    };
+
+    for (var, term) in pending {
+        bindings.bind(var, term);
+    }
+    true
 }
 
-fn rename_expr(expr: Expr, iteration: UniqueID) -> Expr {
-    Box::new(match *expr {
-        ExprKind::Term(term) => {
-            ExprKind::Term(rename_term(term, iteration))
-        },
-        ExprKind::Conjunct(expr1, expr2) => {
-            ExprKind::Conjunct(rename_expr(expr1, iteration), rename_expr(expr2, iteration))
-        },
-    })
+/// Returns true if two terms would unify, without binding any variables
+pub fn unifiable(term1: &Term, term2: &Term, bindings: &Bindings) -> bool {
+    unify_by_reference(term1, term2, bindings).is_some()
+}
+
+/// Unify two terms without modifying the bindings, returning the new bindings needed, so that subterms only need to be
+/// copied once they're actually bound.  A stack of pairs remaining to be unified is used, rather than recursing, so
+/// that deep terms can't overflow the stack
+fn unify_by_reference<'a>(term1: &'a Term, term2: &'a Term, bindings: &'a Bindings) -> Option<Vec<(Variable, &'a Term)>> {
+    let mut pending = vec!();
+    let mut pairs = vec!((term1, term2));
+
+    while let Some((term1, term2)) = pairs.pop() {
+        let term1 = resolve_pending(term1, bindings, &pending);
+        let term2 = resolve_pending(term2, bindings, &pending);
+
+        match (&**term1, &**term2) {
+            (TermKind::Atom(n), _) |
+            (_, TermKind::Atom(n)) if n == "_" => { },
+
+            (TermKind::Atom(n), TermKind::Atom(m)) if n == m => { },
+
+            (TermKind::Integer(n), TermKind::Integer(m)) if n == m => { },
+
+            (TermKind::BigInteger(n), TermKind::BigInteger(m)) if n == m => { },
+
+            (TermKind::Float(n), TermKind::Float(m)) if n == m => { },
+
+            (TermKind::String(n), TermKind::String(m)) if n == m => { },
+
+            (TermKind::Compound(n, args1), TermKind::Compound(m, args2)) if n == m && args1.len() == args2.len() => {
+                pairs.extend(args1.iter().zip(args2.iter()).rev());
+            },
+
+            (TermKind::EmptyList, TermKind::EmptyList) => { },
+
+            (TermKind::List(h1, t1), TermKind::List(h2, t2)) => {
+                pairs.push((t1, t2));
+                pairs.push((h1, h2));
+            },
+
+            (TermKind::Var(n), TermKind::Var(m)) if n == m => { },
+
+            // When binding two variables, the newer one is bound to the older one, so that the variables of the query
+            // stay unbound and are the ones shown in answers
+            (TermKind::Var(n), TermKind::Var(m)) if m.id > n.id => {
+                pending.push((*m, term1));
+            },
+
+            (TermKind::Var(n), _) => {
+                pending.push((*n, term2));
+            },
+
+            (_, TermKind::Var(m)) => {
+                pending.push((*m, term1));
+            },
+
+            _ => return None,
+        }
+    }
+    Some(pending)
 }
 
+fn resolve_pending<'a>(mut term: &'a Term, bindings: &'a Bindings, pending: &[(Variable, &'a Term)]) -> &'a Term {
+    loop {
+        term = bindings.resolve(term);
+        match &**term {
+            TermKind::Var(var) => match pending.iter().find(|(pending_var, _)| pending_var == var) {
+                Some((_, value)) => { term = value; },
+                None => return term,
+            },
+            _ => return term,
+        }
+    }
+}
@@ -1,21 +1,81 @@
 
+use std::time::{ Duration, Instant };
+
 use crate::parser::{ parse, parse_query };
-use crate::solver::{ Database, Query, Partial };
+use crate::solver::{ Database, Query, Solution };
 
 #[allow(dead_code)]
-pub fn solve_program_with_query(program: &str, query: &str) -> Partial {
+pub fn solve_program_with_query(program: &str, query: &str) -> Solution {
     let db = Database::new(parse(program).unwrap());
     let query = Query::new(parse_query(query).unwrap());
     query.solve(&db).unwrap()
 }
 
+#[allow(dead_code)]
+pub fn all_solutions_with_query(program: &str, query: &str) -> Vec<String> {
+    let db = Database::new(parse(program).unwrap());
+    let query = Query::new(parse_query(query).unwrap());
+    query.solutions(&db).map(|solution| format!("{}", solution.result)).collect()
+}
+
+/// Returns each answer to the query, as it would be printed by the REPL
+#[allow(dead_code)]
+pub fn all_answers_with_query(program: &str, query: &str) -> Vec<String> {
+    let db = Database::new(parse(program).unwrap());
+    let query = Query::new(parse_query(query).unwrap());
+    query.solutions(&db).map(|solution| format!("{}", solution.answer)).collect()
+}
+
+/// Returns the uncaught error raised while looking for the first solution to the query
+#[allow(dead_code)]
+pub fn error_for_query(program: &str, query: &str) -> Option<String> {
+    let db = Database::new(parse(program).unwrap());
+    let query = Query::new(parse_query(query).unwrap());
+    let mut solutions = query.solutions(&db);
+    solutions.next();
+    solutions.error().map(|error| format!("{}", error))
+}
+
+#[allow(dead_code)]
+pub fn benchmark_program_with_query(program: &str, query: &str, iterations: usize) -> (Duration, Solution) {
+    let db = Database::new(parse(program).unwrap());
+    let query = Query::new(parse_query(query).unwrap());
+
+    let start = Instant::now();
+    let mut last = None;
+    for _ in 0..iterations {
+        for solution in query.solutions(&db) {
+            last = Some(solution);
+        }
+    }
+    (start.elapsed(), last.expect("the benchmark query has no solution"))
+}
+
+#[allow(dead_code)]
+const NAIVE_REVERSE: &str = "
+    append([], L, L).
+    append([H|T], L, [H|R]) :- append(T, L, R).
+    nrev([], []).
+    nrev([H|T], R) :- nrev(T, RT), append(RT, [H], R).
+";
+
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
-    use crate::tests::{ solve_program_with_query };
+    use crate::parser::{ Token, ParseErrorKind, parse, parse_with, parse_query, parse_query_with, parse_recovering };
+    use crate::operators::Operators;
+    use crate::atoms::{ Atom, TRUE, FAIL, CUT, SOFT_CUT, EXIT_CATCH };
+    use crate::tree::{ Clause, TermKind };
+    use crate::writer::{ format_clause, format_listing };
+    use crate::editor::{ Key, LineBuffer, read_key };
+    use crate::completions;
+    use crate::solver::{ Database, Query };
+    use std::time::Duration;
+    use crate::tests::{ solve_program_with_query, all_solutions_with_query, all_answers_with_query, error_for_query, benchmark_program_with_query, NAIVE_REVERSE };
 
     #[test]
     fn basic_backtracking_test() {
-	let partial = solve_program_with_query("
+	let solution = solve_program_with_query("
         female(marge).
         female(lise).
         male(homer).
@@ -30,12 +90,12 @@ mod tests {
         father(X, bart).
     	");
 
-	assert_eq!(format!("{}", partial.result), "father(homer, bart)");
+	assert_eq!(format!("{}", solution.result), "father(homer, bart)");
     }
 
     #[test]
     fn basic_list_test() {
-	let partial = solve_program_with_query("
+	let solution = solve_program_with_query("
         test([]).
         test([X|Xs]) :- test(Xs).
         ",
@@ -43,12 +103,12 @@ mod tests {
         test([thing, stuff, cat]).
         ");
 
-	assert_eq!(format!("{}", partial.result), "test([thing, stuff, cat])");
+	assert_eq!(format!("{}", solution.result), "test([thing, stuff, cat])");
     }
 
     #[test]
     fn list_append_test() {
-	let partial = solve_program_with_query("
+	let solution = solve_program_with_query("
         append([], Ys, Ys).
         append([X|Xs], Ys, [X|Zs]) :- append(Xs, Ys, Zs).
         ",
@@ -56,12 +116,12 @@ mod tests {
         append([thing, stuff, cat], [more, cat, stuff], Zs).
         ");
 
-	assert_eq!(format!("{}", partial.result), "append([thing, stuff, cat], [more, cat, stuff], [thing, stuff, cat, more, cat, stuff])");
+	assert_eq!(format!("{}", solution.result), "append([thing, stuff, cat], [more, cat, stuff], [thing, stuff, cat, more, cat, stuff])");
     }
 
     #[test]
     fn list_member_true_test() {
-	let partial = solve_program_with_query("
+	let solution = solve_program_with_query("
         member(X, [X|Xs]).
         member(X, [Y|Xs]) :- member(X, Xs).
         ",
@@ -69,12 +129,12 @@ mod tests {
         member(cat, [thing, cat, stuff]).
         ");
 
-	assert_eq!(format!("{}", partial.result), "member(cat, [thing, cat, stuff])");
+	assert_eq!(format!("{}", solution.result), "member(cat, [thing, cat, stuff])");
     }
 
     #[test]
     fn list_delete_test() {
-	let partial = solve_program_with_query("
+	let solution = solve_program_with_query("
         delete(X, [], []).
         delete(X, [X|Ys], Zs) :-  delete(X, Ys, Zs).
         delete(X, [Y|Ys], [Y|Zs]) :- X \\= Y, delete(X, Ys, Zs).
@@ -83,12 +143,12 @@ mod tests {
         delete(cat, [cat, thing, stuff, stuff, cat], Ys).
         ");
 
-	assert_eq!(format!("{}", partial.result), "delete(cat, [cat, thing, stuff, stuff, cat], [thing, stuff, stuff])");
+	assert_eq!(format!("{}", solution.result), "delete(cat, [cat, thing, stuff, stuff, cat], [thing, stuff, stuff])");
     }
 
     #[test]
     fn list_reverse_test() {
-	let partial = solve_program_with_query("
+	let solution = solve_program_with_query("
         reverse(X, Y) :- reverse(X, Y, []).
         reverse([], Z, Z).
         reverse([H|T], Z, Acc) :- reverse(T, Z, [H|Acc]).
@@ -97,12 +157,12 @@ mod tests {
         reverse([cat, dog, bird], X).
         ");
 
-	assert_eq!(format!("{}", partial.result), "reverse([cat, dog, bird], [bird, dog, cat])");
+	assert_eq!(format!("{}", solution.result), "reverse([cat, dog, bird], [bird, dog, cat])");
     }
 
     #[test]
     fn integer_highest_test() {
-	let partial = solve_program_with_query("
+	let solution = solve_program_with_query("
         highest(X, [X|[]]).
         highest(X, [X|Xs]) :- highest(Y, Xs), X >= Y.
         highest(Y, [X|Xs]) :- highest(Y, Xs), X < Y.
@@ -111,12 +171,12 @@ mod tests {
         highest(X, [1, 8, 904, 234, 42]).
         ");
 
-	assert_eq!(format!("{}", partial.result), "highest(904, [1, 8, 904, 234, 42])");
+	assert_eq!(format!("{}", solution.result), "highest(904, [1, 8, 904, 234, 42])");
     }
 
     #[test]
     fn integer_nth() {
-	let partial = solve_program_with_query("
+	let solution = solve_program_with_query("
         nth([X|Xs], 0, X).
         nth([S|Xs], N, Y) :- M is N - 1, nth(Xs, M, Y). 
         ",
@@ -124,12 +184,12 @@ mod tests {
         nth([1, 8, 904, 234, 42], 3, X).
         ");
 
-	assert_eq!(format!("{}", partial.result), "nth([1, 8, 904, 234, 42], 3, 234)");
+	assert_eq!(format!("{}", solution.result), "nth([1, 8, 904, 234, 42], 3, 234)");
     }
 
     #[test]
     fn list_quicksort() {
-	let partial = solve_program_with_query("
+	let solution = solve_program_with_query("
         append([], Ys, Ys).
         append([X|Xs], Ys, [X|Zs]) :- append(Xs, Ys, Zs).
 
@@ -144,7 +204,1279 @@ mod tests {
         quicksort([1, 8, 904, 234, 42], Sorted).
         ");
 
-	assert_eq!(format!("{}", partial.result), "quicksort([1, 8, 904, 234, 42], [1, 8, 42, 234, 904])");
+	assert_eq!(format!("{}", solution.result), "quicksort([1, 8, 904, 234, 42], [1, 8, 42, 234, 904])");
     }
-}
 
+    #[test]
+    fn list_append_all_solutions() {
+	let solutions = all_solutions_with_query("
+        append([], Ys, Ys).
+        append([X|Xs], Ys, [X|Zs]) :- append(Xs, Ys, Zs).
+        ",
+        "
+        append(X, Y, [1, 2, 3]).
+        ");
+
+	assert_eq!(solutions, vec!(
+            "append([], [1, 2, 3], [1, 2, 3])",
+            "append([1], [2, 3], [1, 2, 3])",
+            "append([1, 2], [3], [1, 2, 3])",
+            "append([1, 2, 3], [], [1, 2, 3])",
+        ));
+    }
+
+    #[test]
+    fn nested_subgoal_all_solutions() {
+	let solutions = all_solutions_with_query("
+        parent(marge, bart).
+        parent(marge, lisa).
+        parent(homer, bart).
+        parent(homer, lisa).
+        sibling(X, Y) :- parent(P, X), parent(P, Y), X \\= Y.
+        ",
+        "
+        sibling(X, Y).
+        ");
+
+	assert_eq!(solutions, vec!(
+            "sibling(bart, lisa)",
+            "sibling(lisa, bart)",
+            "sibling(bart, lisa)",
+            "sibling(lisa, bart)",
+        ));
+    }
+
+    #[test]
+    fn solutions_dropped_early() {
+	let db = Database::new(parse("
+        nat(0).
+        nat(N) :- nat(M), N is M + 1.
+        ").unwrap());
+	let query = Query::new(parse_query("nat(X).").unwrap());
+
+	let solutions = query.solutions(&db).take(3).map(|solution| format!("{}", solution.result)).collect::<Vec<String>>();
+	assert_eq!(solutions, vec!("nat(0)", "nat(1)", "nat(2)"));
+    }
+
+    #[test]
+    fn deep_list_walk() {
+	let solution = solve_program_with_query("
+        make(0, []).
+        make(N, [N|Xs]) :- N > 0, M is N - 1, make(M, Xs).
+        test([]).
+        test([X|Xs]) :- test(Xs).
+        walk(N) :- make(N, Xs), test(Xs).
+        ",
+        "
+        walk(100000).
+        ");
+
+	assert_eq!(format!("{}", solution.result), "walk(100000)");
+    }
+
+    #[test]
+    fn deep_non_tail_recursion() {
+	let solution = solve_program_with_query("
+        make(0, []).
+        make(N, [N|Xs]) :- N > 0, M is N - 1, make(M, Xs).
+        len([], 0).
+        len([X|Xs], N) :- len(Xs, M), N is M + 1.
+        count(N, L) :- make(N, Xs), len(Xs, L).
+        ",
+        "
+        count(100000, L).
+        ");
+
+	assert_eq!(format!("{}", solution.result), "count(100000, 100000)");
+    }
+
+    #[test]
+    fn deep_list_result() {
+	let solution = solve_program_with_query("
+        make(0, []).
+        make(N, [N|Xs]) :- N > 0, M is N - 1, make(M, Xs).
+        ",
+        "
+        make(100000, Xs).
+        ");
+
+	assert!(format!("{}", solution.result).starts_with("make(100000, [100000, 99999, 99998, "));
+    }
+
+    #[test]
+    fn cut_at_end_of_body() {
+	let solutions = all_solutions_with_query("
+        max(X, Y, X) :- X >= Y, !.
+        max(X, Y, Y).
+        ",
+        "
+        max(3, 1, M).
+        ");
+
+	assert_eq!(solutions, vec!("max(3, 1, 3)"));
+    }
+
+    #[test]
+    fn cut_after_several_goals() {
+	let solutions = all_solutions_with_query("
+        member(X, [X|Xs]).
+        member(X, [Y|Xs]) :- member(X, Xs).
+        first(X, Xs) :- member(X, Xs), X \\= z, !.
+        first(none, Xs).
+        ",
+        "
+        first(X, [a, b, c]).
+        ");
+
+	assert_eq!(solutions, vec!("first(a, [a, b, c])"));
+    }
+
+    #[test]
+    fn cut_only_prunes_its_own_predicate() {
+	let solutions = all_solutions_with_query("
+        member(X, [X|Xs]).
+        member(X, [Y|Xs]) :- member(X, Xs).
+        once_member(X, Xs) :- member(X, Xs), !.
+        pair(X, Y) :- member(X, [1, 2]), once_member(Y, [a, b]).
+        ",
+        "
+        pair(X, Y).
+        ");
+
+	assert_eq!(solutions, vec!("pair(1, a)", "pair(2, a)"));
+    }
+
+    #[test]
+    fn cut_is_local_to_call() {
+	let solutions = all_solutions_with_query("
+        member(X, [X|Xs]).
+        member(X, [Y|Xs]) :- member(X, Xs).
+        test(X) :- member(X, [a, b, c]), call(!).
+        ",
+        "
+        test(X).
+        ");
+
+	assert_eq!(solutions, vec!("test(a)", "test(b)", "test(c)"));
+    }
+
+    #[test]
+    fn disjunction() {
+	let solutions = all_solutions_with_query("
+        q(a).
+        q(b).
+        r(c).
+        p(X) :- q(X) ; r(X).
+        ",
+        "
+        p(X).
+        ");
+
+	assert_eq!(solutions, vec!("p(a)", "p(b)", "p(c)"));
+    }
+
+    #[test]
+    fn bar_as_disjunction() {
+	let program = "
+        q(a).
+        q(b).
+        r(c).
+        p(X) :- (q(X) | r(X)).
+        ";
+
+	// An infix bar is read as `;`, so it's a disjunction in a clause body, while it still separates a list's tail
+	assert_eq!(all_solutions_with_query(program, "p(X)."), vec!("p(a)", "p(b)", "p(c)"));
+	assert_eq!(format!("{}", parse_query("(a | b).").unwrap()), ";(a, b)");
+	assert_eq!(all_answers_with_query(program, "[H | T] = [1, 2]."), vec!("H = 1,\nT = [2]"));
+    }
+
+    #[test]
+    fn cut_inside_disjunction() {
+	let solutions = all_solutions_with_query("
+        t(X) :- (X is 1, ! ; X is 2).
+        t(3).
+        ",
+        "
+        t(X).
+        ");
+
+	assert_eq!(solutions, vec!("t(1)"));
+    }
+
+    #[test]
+    fn if_then_else() {
+	let solutions = all_solutions_with_query("
+        abs(X, Y) :- (X < 0 -> Y is 0 - X ; Y is X).
+        test(X, Y, Z) :- N is 0 - X, abs(N, Y), abs(Y, Z).
+        ",
+        "
+        test(5, Y, Z).
+        ");
+
+	assert_eq!(solutions, vec!("test(5, 5, 5)"));
+    }
+
+    #[test]
+    fn if_then_else_commits_to_condition() {
+	let solutions = all_solutions_with_query("
+        member(X, [X|Xs]).
+        member(X, [Y|Xs]) :- member(X, Xs).
+        first(X, Xs) :- member(X, Xs) -> true ; X is 0.
+        ",
+        "
+        first(X, [a, b, c]).
+        ");
+
+	assert_eq!(solutions, vec!("first(a, [a, b, c])"));
+    }
+
+    #[test]
+    fn if_then_without_else_fails() {
+	let solutions = all_solutions_with_query("
+        member(X, [X|Xs]).
+        member(X, [Y|Xs]) :- member(X, Xs).
+        test(X) :- (member(X, []) -> true).
+        test(other).
+        ",
+        "
+        test(X).
+        ");
+
+	assert_eq!(solutions, vec!("test(other)"));
+    }
+
+    #[test]
+    fn soft_cut() {
+	let solutions = all_solutions_with_query("
+        member(X, [X|Xs]).
+        member(X, [Y|Xs]) :- member(X, Xs).
+        test(X, Xs) :- (member(X, Xs) *-> true ; X is 0).
+        both(X, Y) :- test(X, [a, b]), test(Y, []).
+        ",
+        "
+        both(X, Y).
+        ");
+
+	assert_eq!(solutions, vec!("both(a, 0)", "both(b, 0)"));
+    }
+
+    #[test]
+    fn control_constructs_in_call() {
+	let solutions = all_solutions_with_query("
+        member(X, [X|Xs]).
+        member(X, [Y|Xs]) :- member(X, Xs).
+        test(X) :- call((member(X, [a, b]), X \\= a ; X is 1)).
+        first(X) :- call((member(X, [a, b]) -> true ; X is 1)).
+        ",
+        "
+        test(X).
+        ");
+
+	assert_eq!(solutions, vec!("test(b)", "test(1)"));
+
+	let solution = solve_program_with_query("
+        member(X, [X|Xs]).
+        member(X, [Y|Xs]) :- member(X, Xs).
+        first(X) :- call((member(X, [a, b]) -> true ; X is 1)).
+        ",
+        "
+        first(X).
+        ");
+
+	assert_eq!(format!("{}", solution.result), "first(a)");
+    }
+
+    #[test]
+    fn negation_as_failure() {
+	let solutions = all_solutions_with_query("
+        member(X, [X|Xs]).
+        member(X, [Y|Xs]) :- member(X, Xs).
+        missing(X, Xs) :- \\+ member(X, Xs).
+        absent(X, Xs) :- not(member(X, Xs)).
+        test(X) :- member(X, [a, b, c, d]), missing(X, [a, c]), absent(X, [d]).
+        ",
+        "
+        test(X).
+        ");
+
+	assert_eq!(solutions, vec!("test(b)"));
+    }
+
+    #[test]
+    fn negation_with_infix_operand() {
+	let solution = solve_program_with_query("
+        delete(X, [], []).
+        delete(X, [X|Ys], Zs) :- delete(X, Ys, Zs).
+        delete(X, [Y|Ys], [Y|Zs]) :- \\+ X = Y, delete(X, Ys, Zs).
+        ",
+        "
+        delete(cat, [cat, thing, stuff, stuff, cat], Ys).
+        ");
+
+	assert_eq!(format!("{}", solution.result), "delete(cat, [cat, thing, stuff, stuff, cat], [thing, stuff, stuff])");
+    }
+
+    #[test]
+    fn variable_goal_is_called() {
+	let solutions = all_solutions_with_query("
+        member(X, [X|Xs]).
+        member(X, [Y|Xs]) :- member(X, Xs).
+        apply(G) :- G.
+        once(G) :- G, !.
+        test(X, Y) :- apply(member(X, [a, b])), once(member(Y, [c, d])).
+        ",
+        "
+        test(X, Y).
+        ");
+
+	assert_eq!(solutions, vec!("test(a, c)", "test(b, c)"));
+    }
+
+    #[test]
+    fn operator_associativity() {
+	let solution = solve_program_with_query("
+        calc(A, B, C, D) :- A is 1 - 2 - 3, B is 2 + 3 * 4, C is (2 + 3) * 4, D is 12 / 2 / 3.
+        ",
+        "
+        calc(A, B, C, D).
+        ");
+
+	assert_eq!(format!("{}", solution.result), "calc(-4, 14, 20, 2)");
+    }
+
+    #[test]
+    fn operator_terms() {
+	let solution = solve_program_with_query("
+        expr((a :- b), (c ; d -> e), - 1, - (1), \\+ (f, g), [-, +], 'X').
+        ",
+        "
+        expr(A, B, C, D, E, F, G).
+        ");
+
+	assert_eq!(format!("{}", solution.result), "expr(:-(a, b), ;(c, ->(d, e)), -1, -(1), \\+(,(f, g)), [-, +], X)");
+    }
+
+    #[test]
+    fn operator_directive() {
+	let clauses = parse("
+        :- op(700, xfx, likes).
+        :- op(200, xf, please).
+        bob likes cheese.
+        go please.
+        ask(X) :- X please.
+        ").unwrap();
+	let db = Database::new(clauses);
+
+	let query = Query::new(parse_query_with("Who likes What.", &db.operators()).unwrap());
+	assert_eq!(format!("{}", query.solve(&db).unwrap().result), "likes(bob, cheese)");
+
+	let query = Query::new(parse_query_with("ask(go).", &db.operators()).unwrap());
+	assert_eq!(format!("{}", query.solve(&db).unwrap().result), "ask(go)");
+    }
+
+    #[test]
+    fn operator_builtin() {
+	let db = Database::new(parse("
+        :- op(700, xfx, likes).
+        ").unwrap());
+
+	let query = Query::new(parse_query("op(200, xfy, loves).").unwrap());
+	assert!(query.solve(&db).is_some());
+	assert!(parse_query_with("a loves b loves c.", &db.operators()).is_ok());
+	assert!(parse_query("a loves b loves c.").is_err());
+
+	let query = Query::new(parse_query("op(0, xfx, likes).").unwrap());
+	assert!(query.solve(&db).is_some());
+	assert!(parse_query_with("a likes b.", &db.operators()).is_err());
+    }
+
+    #[test]
+    fn parse_error_position() {
+	let err = parse("
+        parent(marge, bart).
+        parent(homer bart).
+        ").unwrap_err();
+
+	assert_eq!(err.kind, ParseErrorKind::UnexpectedToken(Token::Word("bart".to_string())));
+	assert_eq!((err.span.line, err.span.column), (3, 22));
+	assert_eq!(err.expected, vec!("`,`", "`)`"));
+	assert_eq!(format!("{}", err), "line 3, column 22: unexpected `bart`, expected `,` or `)`\n        parent(homer bart).\n                     ^^^^");
+    }
+
+    #[test]
+    fn parse_error_at_end_of_clause() {
+	let err = parse("father(X, Y) :- parent(X, Y) male(X).").unwrap_err();
+	assert_eq!(err.kind, ParseErrorKind::UnexpectedToken(Token::Functor("male".to_string())));
+	assert_eq!(err.span.column, 30);
+
+	let err = parse_query("father(X, Y)").unwrap_err();
+	assert_eq!(err.kind, ParseErrorKind::UnexpectedEof);
+	assert_eq!(err.expected, vec!("an operator", "`.`"));
+    }
+
+    #[test]
+    fn parse_recovers_after_errors() {
+	let (clauses, errors) = parse_recovering("
+        a(1).
+        b(1 2).
+        c(X) :- a(X.
+        d(3).
+        e(.
+        ", &mut Operators::default());
+
+	assert_eq!(clauses, parse("a(1). d(3).").unwrap());
+	assert_eq!(errors.iter().map(|err| err.span.line).collect::<Vec<usize>>(), vec!(3, 4, 6));
+    }
+
+    #[test]
+    fn float_arithmetic() {
+	let solution = solve_program_with_query("
+        calc(A, B, C, D, E, F) :- A is 3.14 * 2, B is 7 / 2, C is 7 // 2, D is 6 / 2, E is 1 + 0.5, F is 2.0e-5 / 2.
+        ",
+        "
+        calc(A, B, C, D, E, F).
+        ");
+
+	assert_eq!(format!("{}", solution.result), "calc(6.28, 3.5, 3, 3, 1.5, 1.0e-5)");
+    }
+
+    #[test]
+    fn float_functions() {
+	let solution = solve_program_with_query("
+        calc(A, B, C, D, E, F, G) :- A is sqrt(16), B is float(3), C is truncate(- 2.7), D is round(2.5), E is exp(0), F is log(1), G is sin(0.0).
+        ",
+        "
+        calc(A, B, C, D, E, F, G).
+        ");
+
+	assert_eq!(format!("{}", solution.result), "calc(4.0, 3.0, -2, 3, 1.0, 0.0, 0.0)");
+    }
+
+    #[test]
+    fn float_comparison() {
+	let solutions = all_solutions_with_query("
+        num(1).
+        num(1.5).
+        num(2).
+        num(2.0).
+        between(X) :- num(X), X > 1, X =< 2.0.
+        ",
+        "
+        between(X).
+        ");
+
+	assert_eq!(solutions, vec!("between(1.5)", "between(2)", "between(2.0)"));
+    }
+
+    #[test]
+    fn big_integer_arithmetic() {
+	let solution = solve_program_with_query("
+        factorial(0, 1).
+        factorial(N, F) :- N > 0, M is N - 1, factorial(M, G), F is N * G.
+        ",
+        "
+        factorial(30, F).
+        ");
+
+	assert_eq!(format!("{}", solution.result), "factorial(30, 265252859812191058636308480000000)");
+    }
+
+    #[test]
+    fn big_integer_boundary() {
+	let solution = solve_program_with_query("
+        calc(A, B, C, D, E, F, G) :-
+            A is 9223372036854775807 + 1,
+            B is A - 1,
+            C is 0 - 9223372036854775807 - 1 - 1,
+            D is 123456789012345678901234567890 // 1234567890123,
+            E is 265252859812191058636308480000000 / 10000000,
+            F is truncate(1.0e20),
+            G is A / 2.0,
+            A > B, B < A, C < B, 1.0e30 > A, A =< A.
+        ",
+        "
+        calc(A, B, C, D, E, F, G).
+        ");
+
+	assert_eq!(format!("{}", solution.result), "calc(9223372036854775808, 9223372036854775807, -9223372036854775809, 100000000000036999, 26525285981219105863630848, 100000000000000000000, 4.611686018427388e18)");
+    }
+
+    #[test]
+    fn arithmetic_functions() {
+	let solution = solve_program_with_query("
+        calc([A, B, C, D, E, F, G, H, I, J, K, M]) :- A is 7 mod -2, B is -7 rem 2, C is -7 // 2, D is -7 div 2, E is abs(-3), F is sign(-2.5),
+            G is min(2, 1.5), H is max(2, 1.5), I is 2 ** 3, J is 2 ^ 100, K is gcd(12, -18), M is - (3 - 5).
+        ",
+        "
+        calc(L).
+        ");
+
+	assert_eq!(format!("{}", solution.result), "calc([-1, -1, -3, -4, 3, -1.0, 1.5, 2, 8.0, 1267650600228229401496703205376, 6, 2])");
+    }
+
+    #[test]
+    fn arithmetic_bitwise() {
+	let solution = solve_program_with_query("
+        calc([A, B, C, D, E, F, G, H, I, J]) :- A is 12 /\\ 10, B is 12 \\/ 3, C is 12 xor 10, D is \\ 5, E is 1 << 70, F is -16 >> 2,
+            G is -1 >> 100, H is (1 << 70) /\\ -(1 << 70), I is msb(1000), J is (1 << 70) xor -1.
+        ",
+        "
+        calc(L).
+        ");
+
+	assert_eq!(format!("{}", solution.result), "calc([8, 15, 6, -6, 1180591620717411303424, -4, -1, 1180591620717411303424, 9, -1180591620717411303425])");
+    }
+
+    #[test]
+    fn arithmetic_comparison() {
+	let solutions = all_solutions_with_query("
+        test(a) :- 1 + 1 =:= 2.0.
+        test(b) :- 1 =\\= 1.0.
+        test(c) :- 2 ** 0.5 < 3 / 2.
+        test(d) :- 10 >= 2 * 5.
+        ",
+        "
+        test(X).
+        ");
+
+	assert_eq!(solutions, vec!("test(a)", "test(c)", "test(d)"));
+    }
+
+    #[test]
+    fn arithmetic_errors() {
+	let program = "test(X) :- X is Y + 1.";
+	assert_eq!(error_for_query(program, "test(X)."), Some("error(instantiation_error, context(/(is, 2), _))".to_string()));
+	assert_eq!(error_for_query("", "X is foo + 1."), Some("error(type_error(evaluable, /(foo, 0)), context(/(is, 2), _))".to_string()));
+	assert_eq!(error_for_query("", "X is 1 / 0."), Some("error(evaluation_error(zero_divisor), context(/(is, 2), _))".to_string()));
+	assert_eq!(error_for_query("", "X is 1.5 mod 2."), Some("error(type_error(integer, 1.5), context(/(is, 2), _))".to_string()));
+	assert_eq!(error_for_query("", "a < 1."), Some("error(type_error(evaluable, /(a, 0)), context(/(<, 2), _))".to_string()));
+	assert_eq!(error_for_query("", "X is 1 + 1."), None);
+    }
+
+    #[test]
+    fn catch_and_throw() {
+	let program = "
+        one(1).
+        two(2).
+        risky(X) :- one(X), throw(oops).
+        recovered(X) :- catch(risky(X), oops, two(X)).
+        caught(T) :- catch(X is foo + 1, error(type_error(T, _), _), true).
+        ";
+
+	// Bindings made by the goal are undone before the recovery goal is called
+	assert_eq!(all_solutions_with_query(program, "recovered(X)."), vec!("recovered(2)"));
+	assert_eq!(all_solutions_with_query(program, "caught(T)."), vec!("caught(evaluable)"));
+	assert_eq!(all_solutions_with_query(program, "catch(throw(ball(1)), ball(X), true)."), vec!("catch(throw(ball(1)), ball(1), true)"));
+    }
+
+    #[test]
+    fn catch_rethrow() {
+	let program = "
+        inner :- catch(throw(outer_ball), inner_ball, fail).
+        outer(X) :- catch(inner, X, true).
+        ";
+
+	assert_eq!(all_solutions_with_query(program, "outer(X)."), vec!("outer(outer_ball)"));
+	assert_eq!(error_for_query(program, "inner."), Some("outer_ball".to_string()));
+	assert_eq!(error_for_query("", "throw(X)."), Some("error(instantiation_error, context(/(throw, 1), _))".to_string()));
+    }
+
+    #[test]
+    fn catch_scope() {
+	let program = "
+        num(1).
+        num(2).
+        num(3).
+        late(X) :- catch(num(X), _, true), X > 1, throw(too_late).
+        retry(X) :- catch(num(X), _, true), X >= 2.
+        ";
+
+	// Once the goal has exited, the catch is no longer active even though it can be backtracked into
+	assert_eq!(error_for_query(program, "late(X)."), Some("too_late".to_string()));
+	assert_eq!(all_solutions_with_query(program, "retry(X)."), vec!("retry(2)", "retry(3)"));
+    }
+
+    #[test]
+    fn builtin_errors() {
+	assert_eq!(error_for_query("", "call(X)."), Some("error(instantiation_error, context(/(call, 1), _))".to_string()));
+	assert_eq!(error_for_query("", "call(1, a)."), Some("error(type_error(callable, 1), context(/(call, 2), _))".to_string()));
+	assert_eq!(error_for_query("", "op(1300, xfx, foo)."), Some("error(domain_error(operator_priority, 1300), context(/(op, 3), _))".to_string()));
+	assert_eq!(error_for_query("", "op(700, abc, foo)."), Some("error(domain_error(operator_specifier, abc), context(/(op, 3), _))".to_string()));
+	assert_eq!(error_for_query("", "op(700, xfx, [foo, 1])."), Some("error(type_error(atom, 1), context(/(op, 3), _))".to_string()));
+    }
+
+    #[test]
+    fn unknown_procedure() {
+	let program = "
+        known(1).
+        test(X) :- knwon(X).
+        ";
+
+	assert_eq!(error_for_query(program, "test(X)."), Some("error(existence_error(procedure, /(knwon, 1)), context(/(knwon, 1), _))".to_string()));
+	assert_eq!(error_for_query(program, "catch(test(X), error(existence_error(procedure, P), _), true)."), None);
+	// A defined predicate with no matching clauses fails as usual
+	assert_eq!(error_for_query(program, "known(2)."), None);
+	assert_eq!(all_solutions_with_query(program, "known(2)."), Vec::<String>::new());
+    }
+
+    #[test]
+    fn unknown_flag() {
+	let program = "
+        :- set_prolog_flag(unknown, fail).
+        test(X) :- knwon(X).
+        ";
+
+	assert_eq!(error_for_query(program, "test(X)."), None);
+	assert_eq!(all_solutions_with_query(program, "current_prolog_flag(unknown, V)."), vec!("current_prolog_flag(unknown, fail)"));
+	assert_eq!(all_solutions_with_query(program, "current_prolog_flag(F, fail)."), vec!("current_prolog_flag(unknown, fail)"));
+	assert_eq!(error_for_query("", "set_prolog_flag(unknown, maybe)."), Some("error(domain_error(flag_value, +(unknown, maybe)), context(/(set_prolog_flag, 2), _))".to_string()));
+	assert_eq!(error_for_query("", "set_prolog_flag(nothing, fail)."), Some("error(domain_error(prolog_flag, nothing), context(/(set_prolog_flag, 2), _))".to_string()));
+    }
+
+    #[test]
+    fn assert_and_retract() {
+	let program = "
+        :- dynamic counter/1.
+        counter(0).
+        increment(N1) :- retract(counter(N)), N1 is N + 1, assertz(counter(N1)).
+        twice(N) :- increment(_), increment(N).
+        order(X) :- assertz(item(b)), asserta(item(a)), assertz(item(c)), item(X).
+        ";
+
+	assert_eq!(all_solutions_with_query(program, "twice(N)."), vec!("twice(2)"));
+	assert_eq!(all_solutions_with_query(program, "order(X)."), vec!("order(a)", "order(b)", "order(c)"));
+	assert_eq!(all_solutions_with_query(program, "retract(counter(X))."), vec!("retract(counter(0))"));
+    }
+
+    #[test]
+    fn logical_update_view() {
+	let program = "
+        :- dynamic fact/1.
+        fact(1).
+        fact(2).
+        grow(X) :- fact(X), Y is X + 10, assertz(fact(Y)).
+        shrink(X) :- retract(fact(X)), assertz(fact(3)).
+        rule(B) :- assertz((greet :- write(hello), nl)), retract((greet :- B)).
+        ";
+
+	// Clauses added while a call is in progress aren't seen by that call, including by retract/1 when backtracking
+	assert_eq!(all_solutions_with_query(program, "grow(X)."), vec!("grow(1)", "grow(2)"));
+	assert_eq!(all_solutions_with_query(program, "shrink(X)."), vec!("shrink(1)", "shrink(2)"));
+	assert_eq!(all_solutions_with_query(program, "rule(B)."), vec!("rule(,(write(hello), nl))"));
+    }
+
+    #[test]
+    fn update_while_iterating() {
+	let program = "
+        :- dynamic item/1.
+        item(1).
+        item(2).
+        item(3).
+        churn(X) :- item(X), asserta(item(0)), retractall(item(3)), assertz(item(4)).
+        fill(0) :- !.
+        fill(N) :- assertz(item(N)), M is N - 1, fill(M).
+        grow(0) :- !.
+        grow(N) :- item(_), asserta(item(N)), M is N - 1, grow(M).
+        shrink :- item(X), retract(item(X)), fail.
+        shrink.
+        ";
+
+	let db = Database::new(parse(program).unwrap());
+	let answers = |query: &str| -> Vec<String> {
+	    Query::new(parse_query(query).unwrap()).solutions(&db).map(|solution| format!("{}", solution.result)).collect()
+	};
+	let count = || db.clauses(Atom::new("item"), 1).unwrap().1.len();
+
+	// A call goes on seeing the clauses which were removed after it was made, and not those which were added
+	assert_eq!(answers("churn(X)."), vec!("churn(1)", "churn(2)", "churn(3)"));
+	assert_eq!(answers("item(X)."), vec!("item(0)", "item(0)", "item(0)", "item(1)", "item(2)", "item(4)", "item(4)", "item(4)"));
+
+	// Clauses are added in place while calls to the predicate are open, rather than copying it, so this takes linear
+	// time even though every clause is added while a new call is waiting to try the rest of the clauses
+	let start = std::time::Instant::now();
+	assert_eq!(answers("fill(20000), grow(20000), !.").len(), 1);
+	assert!(start.elapsed() < Duration::from_secs(10), "asserting 20000 clauses took {:?}", start.elapsed());
+	assert_eq!(count(), 40008);
+
+	assert_eq!(answers("shrink.").len(), 1);
+	assert_eq!(count(), 0);
+	assert_eq!(answers("item(X)."), Vec::<String>::new());
+    }
+
+    #[test]
+    fn retractall_and_abolish() {
+	let program = "
+        :- dynamic item/1.
+        item(1).
+        item(2).
+        item(3).
+        clear(X) :- retractall(item(_)), item(X).
+        removed :- abolish(item/1), item(_).
+        static(1).
+        fresh(X) :- retractall(new(_)), new(X).
+        ";
+
+	assert_eq!(all_solutions_with_query(program, "clear(X)."), Vec::<String>::new());
+	assert_eq!(error_for_query(program, "clear(X)."), None);
+	assert_eq!(error_for_query(program, "fresh(X)."), None);
+	assert_eq!(error_for_query(program, "removed."), Some("error(existence_error(procedure, /(item, 1)), context(/(item, 1), _))".to_string()));
+	assert_eq!(error_for_query(program, "assertz(static(2))."), Some("error(permission_error(modify, static_procedure, /(static, 1)), context(/(assertz, 1), _))".to_string()));
+	assert_eq!(error_for_query(program, "asserta(write(2))."), Some("error(permission_error(modify, static_procedure, /(write, 1)), context(/(asserta, 1), _))".to_string()));
+	assert_eq!(error_for_query(program, "assertz(X)."), Some("error(instantiation_error, context(/(assertz, 1), _))".to_string()));
+	assert_eq!(error_for_query(program, "abolish(foo/a)."), Some("error(type_error(integer, a), context(/(abolish, 1), _))".to_string()));
+    }
+
+    #[test]
+    fn retract_with_indexes() {
+	let program = "
+        :- dynamic item/2.
+        item(1, a).
+        item(2, a).
+        item(3, a).
+        fill(0) :- !.
+        fill(N) :- assertz(item(N, a)), asserta(item(N, b)), M is N - 1, fill(M).
+        remove(0) :- !.
+        remove(N) :- retract(item(N, b)), M is N - 1, remove(M).
+        drain :- retract(item(_, _)), fail.
+        drain.
+        ";
+
+	let db = Database::new(parse(program).unwrap());
+	let answers = |query: &str| -> Vec<String> {
+	    Query::new(parse_query(query).unwrap()).solutions(&db).map(|solution| format!("{}", solution.result)).collect()
+	};
+
+	// Indexes are kept up to date as clauses are added to the start of the predicate and removed
+	assert_eq!(answers("item(2, X)."), vec!("item(2, a)"));
+	assert_eq!(answers("asserta(item(2, c)), asserta(item(4, c))."), vec!(",(asserta(item(2, c)), asserta(item(4, c)))"));
+	assert_eq!(answers("item(2, X)."), vec!("item(2, c)", "item(2, a)"));
+	assert_eq!(answers("retract(item(2, a))."), vec!("retract(item(2, a))"));
+	assert_eq!(answers("item(2, X)."), vec!("item(2, c)"));
+	assert_eq!(answers("item(X, Y)."), vec!("item(4, c)", "item(2, c)", "item(1, a)", "item(3, a)"));
+	assert_eq!(db.indexes(Atom::new("item"), 2), vec!((1, 4)));
+
+	// Retracting each clause only looks at the clauses selected by the index, so this takes linear time
+	let start = std::time::Instant::now();
+	assert_eq!(answers("fill(50000), remove(50000), drain.").len(), 1);
+	assert!(start.elapsed() < Duration::from_secs(30), "retracting 100000 clauses took {:?}", start.elapsed());
+	assert_eq!(answers("item(X, Y)."), Vec::<String>::new());
+    }
+
+    #[test]
+    fn more_answers() {
+	let program = "
+        parent(homer, bart).
+        parent(homer, lisa).
+        ";
+	let db = Database::new(parse(program).unwrap());
+	let has_more = |text: &str| {
+	    let query = Query::new(parse_query(text).unwrap());
+	    let mut solutions = query.solutions(&db);
+	    assert!(solutions.next().is_some(), "{} has no answer", text);
+	    solutions.has_alternatives()
+	};
+
+	// The REPL only asks for more answers when there could be another one
+	assert!(has_more("parent(homer, X)."));
+	assert!(has_more("true ; true."));
+	assert!(has_more("catch(parent(homer, X), _, true)."));
+	assert!(!has_more("parent(homer, lisa)."));
+	assert!(!has_more("catch(true, _, true)."));
+	assert!(!has_more("(true -> true ; true)."));
+	assert!(!has_more("(parent(homer, X) -> true)."));
+	assert!(!has_more("true ; fail."));
+    }
+
+    #[test]
+    fn first_argument_indexing() {
+	let program = "
+        colour(red, 1).
+        colour(green, 2).
+        colour(blue, 3).
+        colour(X, 0) :- X = other.
+        shape(square(_), 4).
+        shape(triangle(_), 3).
+        shape([], 0).
+        shape([_ | _], 1).
+        ";
+
+	let db = Database::new(parse(program).unwrap());
+
+	// A call whose first argument is bound only tries the clauses with the same key, or a variable
+	let query = Query::new(parse_query("shape(triangle(a), N).").unwrap());
+	let mut solutions = query.solutions(&db);
+	assert_eq!(format!("{}", solutions.next().unwrap().result), "shape(triangle(a), 3)");
+	assert!(!solutions.has_alternatives());
+
+	let query = Query::new(parse_query("shape([], N).").unwrap());
+	let mut solutions = query.solutions(&db);
+	assert_eq!(format!("{}", solutions.next().unwrap().result), "shape([], 0)");
+	assert!(!solutions.has_alternatives());
+
+	let query = Query::new(parse_query("colour(green, N).").unwrap());
+	let mut solutions = query.solutions(&db);
+	assert_eq!(format!("{}", solutions.next().unwrap().result), "colour(green, 2)");
+	assert!(solutions.has_alternatives());
+
+	assert_eq!(all_solutions_with_query(program, "colour(X, 2)."), vec!("colour(green, 2)"));
+	assert_eq!(all_solutions_with_query(program, "colour(purple, N)."), Vec::<String>::new());
+	assert_eq!(all_solutions_with_query(program, "shape(S, 3)."), vec!("shape(triangle(_), 3)"));
+    }
+
+    #[test]
+    fn indexing_large_database() {
+	let mut program = String::new();
+	for i in 0..200_000 {
+	    program += &format!("fact({}, value{}).\n", i, i);
+	}
+
+	let db = Database::new(parse(&program).unwrap());
+	let query = Query::new(parse_query("fact(199999, X).").unwrap());
+	let mut solutions = query.solutions(&db);
+	assert_eq!(format!("{}", solutions.next().unwrap().result), "fact(199999, value199999)");
+	assert!(!solutions.has_alternatives());
+    }
+
+    #[test]
+    fn jit_argument_indexing() {
+	let program = "
+        :- dynamic parent/2.
+        parent(abe, homer).
+        parent(mona, homer).
+        parent(homer, bart).
+        parent(marge, bart).
+        parent(homer, lisa).
+        parent(marge, lisa).
+        parent(homer, maggie).
+        parent(marge, maggie).
+        parent(clancy, marge).
+        parent(jacqueline, marge).
+        adopt(X) :- assertz(parent(X, ling)).
+        small(a, 1).
+        small(b, 2).
+        ";
+
+	let db = Database::new(parse(program).unwrap());
+	assert_eq!(db.indexes(Atom::new("parent"), 2), vec!());
+
+	// The index on the second argument is only built once a call has the second argument bound
+	let query = Query::new(parse_query("parent(X, bart).").unwrap());
+	let solutions: Vec<String> = query.solutions(&db).map(|solution| format!("{}", solution.result)).collect();
+	assert_eq!(solutions, vec!("parent(homer, bart)", "parent(marge, bart)"));
+	assert_eq!(db.indexes(Atom::new("parent"), 2), vec!((2, 5)));
+
+	let query = Query::new(parse_query("parent(X, homer).").unwrap());
+	let mut solutions = query.solutions(&db);
+	assert_eq!(format!("{}", solutions.next().unwrap().result), "parent(abe, homer)");
+	assert_eq!(format!("{}", solutions.next().unwrap().result), "parent(mona, homer)");
+	assert!(!solutions.has_alternatives());
+
+	// The most selective index is used when more than one argument is bound
+	let query = Query::new(parse_query("parent(clancy, marge).").unwrap());
+	let mut solutions = query.solutions(&db);
+	assert!(solutions.next().is_some());
+	assert!(!solutions.has_alternatives());
+	assert_eq!(db.indexes(Atom::new("parent"), 2), vec!((1, 6), (2, 5)));
+
+	// Clauses added later are included in the existing indexes
+	assert!(Query::new(parse_query("adopt(apu).").unwrap()).solve(&db).is_some());
+	let query = Query::new(parse_query("parent(X, ling).").unwrap());
+	let solutions: Vec<String> = query.solutions(&db).map(|solution| format!("{}", solution.result)).collect();
+	assert_eq!(solutions, vec!("parent(apu, ling)"));
+	assert_eq!(db.indexes(Atom::new("parent"), 2), vec!((1, 7), (2, 6)));
+
+	// Small predicates are only indexed on their first argument
+	assert_eq!(Query::new(parse_query("small(X, 2).").unwrap()).solutions(&db).count(), 1);
+	assert_eq!(db.indexes(Atom::new("small"), 2), vec!());
+
+	// A call only builds an index on another argument if the indexes it has leave many clauses to try, and only on the
+	// argument which is most selective.  The indexes are kept when clauses are removed
+	let mut program = String::from(":- dynamic same/2.\n");
+	for i in 1..=10 {
+	    program += &format!("same(a, {}).\nfew(k{}, {}).\nflat({}, x).\n", i, i, i, i);
+	}
+
+	let db = Database::new(parse(&program).unwrap());
+	assert_eq!(Query::new(parse_query("few(k3, 3).").unwrap()).solutions(&db).count(), 1);
+	assert_eq!(db.indexes(Atom::new("few"), 2), vec!((1, 10)));
+	assert_eq!(Query::new(parse_query("flat(X, x).").unwrap()).solutions(&db).count(), 10);
+	assert_eq!(db.indexes(Atom::new("flat"), 2), vec!());
+	assert_eq!(Query::new(parse_query("same(a, 3).").unwrap()).solutions(&db).count(), 1);
+	assert_eq!(db.indexes(Atom::new("same"), 2), vec!((1, 1), (2, 10)));
+	assert!(Query::new(parse_query("retract(same(a, 5)).").unwrap()).solve(&db).is_some());
+	assert_eq!(db.indexes(Atom::new("same"), 2), vec!((1, 1), (2, 9)));
+    }
+
+    #[test]
+    fn assert_distinct_variables() {
+	let program = "
+        :- dynamic pair/2.
+        store(Y) :- assertz(pair(X, Y)).
+        test(B) :- store(X), pair(a, B).
+        ";
+
+	// The asserted clause has two variables named X from different renamings, which must stay distinct
+	let solutions = all_solutions_with_query(program, "test(B).");
+	assert_eq!(solutions.len(), 1);
+	assert_ne!(solutions[0], "test(a)");
+    }
+
+    #[test]
+    fn atoms_are_interned() {
+	assert_eq!(Atom::new("foo"), Atom::new(&["fo", "o"].concat()));
+	assert_ne!(Atom::new("foo"), Atom::new("bar"));
+	assert_eq!(Atom::new("foo").as_str(), "foo");
+	assert!(Atom::new("apple") < Atom::new("banana"));
+    }
+
+    #[test]
+    fn answers_use_query_variables() {
+	let program = "
+        same(X, X).
+        wrap(f(Y)).
+        ";
+
+	assert_eq!(all_solutions_with_query(program, "same(A, B)."), vec!("same(A, A)"));
+	assert_eq!(all_solutions_with_query(program, "same(f(A), B)."), vec!("same(f(A), f(A))"));
+	// Variables that don't appear in the query are shown with a generated name
+	assert_eq!(all_solutions_with_query(program, "wrap(A)."), vec!("wrap(f(_G1))"));
+    }
+
+    #[test]
+    fn answers_bind_query_variables() {
+	let program = "
+        likes(mary, wine).
+        likes(john, X) :- likes(X, wine).
+        same(X, X).
+        pair(A, B, p(A, B)).
+        ";
+
+	assert_eq!(all_answers_with_query(program, "likes(Who, What)."), vec!("Who = mary,\nWhat = wine", "Who = john,\nWhat = mary"));
+	assert_eq!(all_answers_with_query(program, "likes(mary, wine)."), vec!("true"));
+	assert_eq!(all_answers_with_query(program, "likes(wine, mary)."), Vec::<String>::new());
+	// Unbound variables are left out, and variables starting with an underscore aren't reported
+	assert_eq!(all_answers_with_query(program, "same(A, A)."), vec!("true"));
+	assert_eq!(all_answers_with_query(program, "likes(_Who, What)."), vec!("What = wine", "What = mary"));
+	assert_eq!(all_answers_with_query(program, "pair(1, B, P)."), vec!("P = p(1, B)"));
+
+	let solution = solve_program_with_query(program, "pair(X, two, P).");
+	assert_eq!(solution.answer.get("P").map(|value| format!("{}", value)), Some("p(X, two)".to_string()));
+	assert_eq!(solution.answer.get("X"), None);
+    }
+
+    #[test]
+    fn compound_queries() {
+	let program = "
+        parent(tom, bob).
+        parent(ann, bob).
+        parent(bob, liz).
+        male(tom).
+        male(bob).
+        ";
+
+	assert_eq!(all_answers_with_query(program, "parent(X, Y), male(X)."), vec!("X = tom,\nY = bob", "X = bob,\nY = liz"));
+	assert_eq!(all_answers_with_query(program, "parent(X, liz) ; male(X)."), vec!("X = bob", "X = tom", "X = bob"));
+	assert_eq!(all_answers_with_query(program, "parent(X, bob), !."), vec!("X = tom"));
+	assert_eq!(all_answers_with_query(program, "parent(X, bob), \\+ male(X)."), vec!("X = ann"));
+	assert_eq!(all_answers_with_query(program, "(male(ann) -> parent(ann, X) ; parent(bob, X))."), vec!("X = liz"));
+	assert_eq!(all_answers_with_query(program, "parent(G, P), parent(P, C)."), vec!("G = tom,\nP = bob,\nC = liz", "G = ann,\nP = bob,\nC = liz"));
+	// The result is still the whole query, with its control constructs as terms
+	assert_eq!(all_solutions_with_query(program, "male(X), parent(X, liz)."), vec!(",(male(bob), parent(bob, liz))"));
+    }
+
+    #[test]
+    fn unify_in_queries() {
+	let program = "
+        parent(tom, bob).
+        parent(bob, liz).
+        ";
+
+	assert_eq!(all_answers_with_query(program, "X = 1."), vec!("X = 1"));
+	assert_eq!(all_answers_with_query(program, "X = f(Y)."), vec!("X = f(Y)"));
+	assert_eq!(all_answers_with_query(program, "X = \"abc\"."), vec!("X = \"abc\""));
+	assert_eq!(all_answers_with_query(program, "X = Y, Y = 2."), vec!("X = 2,\nY = 2"));
+	assert_eq!(all_answers_with_query(program, "f(X, b) = f(a, Y)."), vec!("X = a,\nY = b"));
+	assert_eq!(all_answers_with_query(program, "parent(X, Y), X = bob."), vec!("X = bob,\nY = liz"));
+	assert_eq!(all_answers_with_query(program, "X = 1, X = 2."), Vec::<String>::new());
+	assert_eq!(all_answers_with_query(program, "X \\= 1."), Vec::<String>::new());
+	assert_eq!(all_answers_with_query(program, "f(X, X) \\= f(a, b)."), vec!("true"));
+    }
+
+    #[test]
+    fn consult_and_make() {
+	let dir = std::env::temp_dir().join(format!("conlog_consult_{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let family = dir.join("family.plg");
+	std::fs::write(&family, "grand(X, Z) :- parent(X, Y), parent(Y, Z).\nparent(X, Y) :- father(X, Y).\n").unwrap();
+	std::fs::write(dir.join("fathers.pl"), "father(tom, bob).\nfather(bob, liz).\n").unwrap();
+
+	let db = Database::new(vec!());
+	let answers = |query: &str| -> Vec<String> {
+	    Query::new(parse_query(query).unwrap()).solutions(&db).map(|solution| format!("{}", solution.answer)).collect()
+	};
+
+	// The extension can be left off, and a list of files consults each of them
+	assert_eq!(answers(&format!("consult('{}').", dir.join("family").display())), vec!("true"));
+	assert_eq!(answers(&format!("['{}'].", dir.join("fathers").display())), vec!("true"));
+	assert_eq!(answers("grand(tom, X)."), vec!("X = liz"));
+
+	// Reloading a changed file replaces the clauses it had before
+	std::fs::write(&family, "grand(X, Z) :- father(X, Z).\n").unwrap();
+	let modified = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+	std::fs::File::options().write(true).open(&family).unwrap().set_modified(modified).unwrap();
+	assert_eq!(answers("make."), vec!("true"));
+	assert_eq!(answers("grand(tom, X)."), vec!("X = bob"));
+	assert!(!db.is_defined(Atom::new("parent"), 2));
+
+	let mut solutions = Query::new(parse_query("consult(no_such_file).").unwrap()).solutions(&db);
+	assert!(solutions.next().is_none());
+	assert_eq!(format!("{}", solutions.error().unwrap()), "error(existence_error(source_sink, no_such_file), context(/(consult, 1), _))");
+
+	std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn listing_format() {
+	let clauses = parse("
+        foo(X, Y) :- bar(X), \\+ baz(Y, _Z), qux.
+        foo(X, X).
+        foo(a, b) :- true.
+        ").unwrap();
+
+	let operators = Operators::default();
+	let listed: Vec<String> = clauses.into_iter().map(|clause| match clause {
+	    Clause::Fact(head) => format_clause(&head, None, &operators),
+	    Clause::Rule(head, body) => format_clause(&head, Some(body.into()), &operators),
+	    Clause::Directive(_) => unreachable!(),
+	}).collect();
+	assert_eq!(listed, vec!("foo(A, B) :-\n    bar(A),\n    \\+ baz(B, C),\n    qux.", "foo(A, A).", "foo(a, b)."));
+    }
+
+    #[test]
+    fn listing_reads_back() {
+	// The variables are named the way listing names them, so that the clauses read back are exactly the same
+	let program = "
+        :- op(700, xfy, ==>).
+        'Hello world'(A, 'X', '_y', [], '[]', [a, 'B' | A], ',', '|').
+        :- dynamic counter/1.
+        counter(0).
+        expr(A, B) :-
+            B is (A + 1) * 2 - -1 - (-(1)) + 2 ** (3 ** 4),
+            C = (a :- b, c),
+            D = f((a, b), -, dynamic, - a, - (- a), 1 - (2 - 3), 1.5e-5, \"str\", (a ; b)),
+            \\+ \\+ C = D,
+            (   A > 0
+            ->  true
+            ;   A =:= 0, fail
+            ).
+        ops(A) :-
+            A = (x ==> y ==> z),
+            A \\= ((p ==> q) ==> r).
+        ";
+
+	let mut operators = Operators::default();
+	let clauses = parse_with(program, &mut operators).unwrap();
+	let db = Database::new(clauses.clone());
+	let listing = format_listing(&db, &db.predicate_indicators());
+
+	let mut operators = db.operators().clone();
+	let listed = parse_with(&listing, &mut operators).unwrap_or_else(|error| panic!("{}\n{}", error, listing));
+	assert_eq!(listed, clauses[1..], "{}", listing);
+
+	assert!(listing.contains(":- dynamic counter/1.\n"), "{}", listing);
+	assert!(listing.contains("'Hello world'(A, 'X', '_y', [], '[]', [a, 'B' | A], ',', '|')."), "{}", listing);
+	assert!(listing.contains("    B is (A + 1) * 2 - -1 - -(1) + 2 ** (3 ** 4),\n"), "{}", listing);
+	assert!(listing.contains("    \\+ \\+ C = D,\n    (A > 0 -> true ; A =:= 0, fail).\n"), "{}", listing);
+	assert!(listing.contains("    A \\= ((p ==> q) ==> r).\n"), "{}", listing);
+    }
+
+    #[test]
+    fn quoted_atoms_round_trip() {
+	let program = "quoted('it''s', 'back\\\\slash', 'tab\\tand\\nnewline', '\\'', \"say \"\"hi\"\"\").";
+	let clauses = parse(program).unwrap();
+	let names = match &clauses[..] {
+	    [Clause::Fact(head)] => match &**head {
+		TermKind::Compound(_, args) => args.iter().map(|arg| match &**arg {
+		    TermKind::Atom(name) => name.to_string(),
+		    TermKind::String(text) => text.to_string(),
+		    _ => unreachable!(),
+		}).collect::<Vec<String>>(),
+		_ => unreachable!(),
+	    },
+	    _ => unreachable!(),
+	};
+	assert_eq!(names, vec!("it's", "back\\slash", "tab\tand\nnewline", "'", "say \"hi\""));
+
+	let db = Database::new(clauses.clone());
+	let listing = format_listing(&db, &db.predicate_indicators());
+	assert!(listing.contains("quoted('it''s', 'back\\\\slash', 'tab\\tand\\nnewline', '''', "), "{}", listing);
+	assert_eq!(parse(&listing).unwrap(), clauses, "{}", listing);
+    }
+
+    #[test]
+    fn well_known_atoms() {
+	// The well-known atoms are the ones interned for their names, so they compare equal to atoms that are read
+	for (atom, name) in [(TRUE, "true"), (FAIL, "fail"), (CUT, "!"), (SOFT_CUT, "$soft_cut"), (EXIT_CATCH, "$exit_catch")] {
+	    assert_eq!(atom, Atom::new(name));
+	}
+	assert_eq!(all_answers_with_query("", "(true ; fail ; true)."), vec!("true", "true"));
+    }
+
+    #[test]
+    fn editor_keys() {
+	let mut input: &[u8] = b"a\x1b[A\x1b[3~\x1bOH\x1b[4~\x01\x7f\r\t\xc3\xa9";
+	let mut keys = vec!();
+	while let Some(key) = read_key(&mut input).unwrap() {
+	    keys.push(key);
+	}
+	assert_eq!(keys, vec!(Key::Char('a'), Key::Up, Key::Delete, Key::Home, Key::End, Key::Ctrl('a'), Key::Backspace, Key::Enter, Key::Tab, Key::Char('é')));
+    }
+
+    #[test]
+    fn editor_line_editing() {
+	let mut line = LineBuffer::default();
+	for key in "foo(X).".chars().map(Key::Char) {
+	    line.edit(key);
+	}
+	assert_eq!((line.text().as_str(), line.cursor()), ("foo(X).", 7));
+
+	line.edit(Key::Left);
+	line.edit(Key::Left);
+	line.edit(Key::Backspace);
+	line.edit(Key::Char('Y'));
+	assert_eq!((line.text().as_str(), line.cursor()), ("foo(Y).", 5));
+
+	line.edit(Key::Ctrl('a'));
+	line.edit(Key::Delete);
+	line.edit(Key::Ctrl('e'));
+	line.edit(Key::Right);
+	assert_eq!((line.text().as_str(), line.cursor()), ("oo(Y).", 6));
+
+	line.edit(Key::Home);
+	line.edit(Key::Right);
+	line.edit(Key::Right);
+	line.edit(Key::Ctrl('k'));
+	assert_eq!((line.text().as_str(), line.cursor()), ("oo", 2));
+
+	line.set("bar(Z).");
+	line.edit(Key::Left);
+	line.edit(Key::Ctrl('u'));
+	assert_eq!((line.text().as_str(), line.cursor()), (".", 0));
+	assert!(!line.edit(Key::Tab));
+    }
+
+    #[test]
+    fn tab_completion() {
+	let db = Database::new(parse("
+        murderer(X) :- attire(X, pincushion).
+        attire(mr_boddy, [mustard, muddy]).
+        mustard.
+        ").unwrap());
+
+	let texts = |prefix: &str| -> Vec<String> {
+	    completions(&db, prefix).into_iter().map(|completion| completion.text).collect()
+	};
+	assert_eq!(texts("mu"), vec!("muddy", "murderer(", "mustard"));
+	assert_eq!(texts("as"), vec!("assert(", "asserta(", "assertz("));
+	// Hidden builtins aren't offered
+	assert_eq!(texts("$"), Vec::<String>::new());
+	assert_eq!(completions(&db, "murd")[0].display, "murderer/1");
+
+	let mut line = LineBuffer::default();
+	line.set("murderer(X) :- asse");
+	assert_eq!(line.name_before_cursor(), "asse");
+	assert!(line.complete(&completions(&db, "asse")));
+	assert_eq!(line.text(), "murderer(X) :- assert");
+	// The choices no longer agree on anything more, so they would be listed instead
+	assert!(!line.complete(&completions(&db, "assert")));
+
+	line.set("mur");
+	assert!(line.complete(&completions(&db, "mur")));
+	assert_eq!((line.text().as_str(), line.cursor()), ("murderer(", 9));
+    }
+
+    #[test]
+    fn atoms_follow_updates() {
+	let db = Database::new(parse("
+        :- dynamic colour/1.
+        colour(red).
+        colour(green).
+        likes(mary, [red, wine]).
+        ").unwrap());
+	let atoms = || -> Vec<String> { db.atoms().iter().map(|name| name.to_string()).collect() };
+	let solve = |query: &str| assert!(Query::new(parse_query(query).unwrap()).solve(&db).is_some(), "{} failed", query);
+
+	assert_eq!(atoms(), vec!("green", "mary", "red", "wine"));
+	solve("assertz(colour(teal)), asserta(colour(blue)).");
+	assert_eq!(atoms(), vec!("blue", "green", "mary", "red", "teal", "wine"));
+	// An atom is kept for as long as any clause still uses it
+	solve("retract(colour(red)), retract(colour(teal)).");
+	assert_eq!(atoms(), vec!("blue", "green", "mary", "red", "wine"));
+	solve("abolish(colour/1).");
+	assert_eq!(atoms(), vec!("mary", "red", "wine"));
+    }
+
+    #[test]
+    fn tail_recursion_in_constant_space() {
+	let program = "
+        loop(0).
+        loop(N) :- N > 0, M is N - 1, loop(M).
+        ";
+
+	// Without removing unreachable bindings there would be two for every iteration, and the bindings are collected
+	// several times over the course of the loop
+	let solution = solve_program_with_query(program, "loop(300000).");
+	assert!(solution.bindings.len() < 100_000, "{} bindings kept", solution.bindings.len());
+	// No choice points exist during the loop, so nothing needs to be trailed
+	assert!(solution.bindings.trail_len() < 10, "{} bindings trailed", solution.bindings.trail_len());
+    }
+
+    #[test]
+    fn solve_on_separate_threads() {
+	let threads: Vec<_> = (0..4).map(|i| {
+	    std::thread::spawn(move || {
+		let program = format!("
+                count(0, []).
+                count(N, [item{} | L]) :- N > 0, M is N - 1, count(M, L).
+                ", i);
+		all_solutions_with_query(&program, "count(3, L).")
+	    })
+	}).collect();
+
+	for (i, thread) in threads.into_iter().enumerate() {
+	    let expected = format!("count(3, [item{}, item{}, item{}])", i, i, i);
+	    assert_eq!(thread.join().unwrap(), vec!(expected));
+	}
+    }
+
+    // Benchmarks run on fixed workloads and check the bindings kept and the speed, so that regressions fail the tests.
+    // The speeds they require are well below what an unoptimised build reaches now, but above what the solver reached
+    // in an optimised build before it used a trail (22k LIPS, and 238us per metro1.plg query).  Their timings can be
+    // seen with `cargo test --release benchmark -- --nocapture`
+    #[test]
+    fn benchmark_naive_reverse() {
+        let list: Vec<String> = (1..=500).map(|i| i.to_string()).collect();
+        let (elapsed, solution) = benchmark_program_with_query(NAIVE_REVERSE, &format!("nrev([{}], X).", list.join(", ")), 1);
+
+        // Reversing a list of n elements takes (n + 1)(n + 2) / 2 logical inferences
+        let inferences = 501 * 502 / 2;
+        let lips = inferences as f64 / elapsed.as_secs_f64();
+        println!("naive reverse of 500 elements: {:?} ({:.0} LIPS), {} bindings kept", elapsed, lips, solution.bindings.len());
+
+        // Every inference binds a few variables, but most of them are unreachable once the call returns
+        assert!(solution.bindings.len() < 65536, "{} bindings kept for {} inferences", solution.bindings.len(), inferences);
+        assert_eq!(solution.bindings.trail_len(), 0);
+        assert!(lips > 25_000.0, "naive reverse ran at {:.0} LIPS", lips);
+    }
+
+    #[test]
+    fn benchmark_metro() {
+        let iterations = 2000;
+        let (elapsed, solution) = benchmark_program_with_query(include_str!("../metro1.plg"), "murderer(X).", iterations);
+        let per_query = elapsed / iterations as u32;
+        println!("metro1.plg murderer(X) x{}: {:?} ({:?} per query)", iterations, elapsed, per_query);
+
+        assert!(solution.bindings.len() < 100, "{} bindings kept", solution.bindings.len());
+        assert!(per_query < Duration::from_micros(200), "metro1.plg took {:?} per query", per_query);
+    }
+}
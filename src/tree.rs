@@ -1,27 +1,47 @@
 
 use std::fmt;
+use std::mem;
+use std::rc::Rc;
 use std::fmt::Debug;
 use std::convert::From;
 
+use crate::bigint::BigInt;
+use crate::atoms::Atom;
+
+
+/// A variable, identified by its name and the renaming it belongs to.  Variables read from the source have an id of
+/// 0, and each time a clause is tried, its variables are renamed with a fresh id
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Variable {
+    pub name: Atom,
+    pub id: usize,
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum TermKind {
     EmptyList,
-    Var(String),
-    Atom(String),
+    Var(Variable),
+    Atom(Atom),
     Integer(i64),
+    BigInteger(BigInt),
+    Float(f64),
     String(String),
-    Compound(String, Vec<Term>),
+    Compound(Atom, Vec<Term>),
     List(Term, Term),
 }
 
-pub type Term = Box<TermKind>;
+/// Terms are immutable and reference counted, so that they can share structure, such as when a clause is renamed, or
+/// bindings are substituted into a term
+pub type Term = Rc<TermKind>;
 
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum ExprKind {
     Term(Term),
     Conjunct(Expr, Expr),
+    Disjunct(Expr, Expr),
+    IfThenElse(Expr, Expr, Option<Expr>),
+    SoftCut(Expr, Expr, Option<Expr>),
 }
 
 pub type Expr = Box<ExprKind>;
@@ -32,15 +52,83 @@ pub enum Clause {
     // you could eliminate the fact special case by having a termkind True, which is the rhs of a rule (such that True is a special case rather than a normal Atom)
     Fact(Term),
     Rule(Term, Expr),
+    Directive(Term),
 }
 
 
 impl From<Term> for Expr {
+    /// Convert a term into an expression, interpreting any control constructs in it, so that a term like `(A ; B)`
+    /// can be called as a goal
     fn from(item: Term) -> Self {
+        if let TermKind::Compound(name, args) = &*item {
+            if args.len() == 2 {
+                let (lhs, rhs) = (args[0].clone(), args[1].clone());
+                match (name.as_str(), &*lhs) {
+                    (",", _) => return conjunct(lhs, rhs),
+                    (";", TermKind::Compound(op, cond)) if op == "->" && cond.len() == 2 =>
+                        return if_then_else(cond[0].clone(), cond[1].clone(), Some(rhs.into())),
+                    (";", TermKind::Compound(op, cond)) if op == "*->" && cond.len() == 2 =>
+                        return soft_cut(cond[0].clone(), cond[1].clone(), Some(rhs.into())),
+                    (";", _) => return disjunct(lhs, rhs),
+                    ("->", _) => return if_then_else(lhs, rhs, None),
+                    ("*->", _) => return soft_cut(lhs, rhs, None),
+                    _ => { },
+                }
+            }
+        }
         Box::new(ExprKind::Term(item))
     }
 }
 
+impl From<Atom> for Term {
+    fn from(item: Atom) -> Self {
+        Rc::new(TermKind::Atom(item))
+    }
+}
+
+impl From<Expr> for Term {
+    /// Convert an expression back into an equivalent term, with control constructs represented as compound terms
+    fn from(item: Expr) -> Self {
+        match *item {
+            ExprKind::Term(term) => term,
+            ExprKind::Conjunct(expr1, expr2) => compound(",", vec!(expr1.into(), expr2.into())),
+            ExprKind::Disjunct(expr1, expr2) => compound(";", vec!(expr1.into(), expr2.into())),
+            ExprKind::IfThenElse(cond, then, otherwise) => {
+                let term = compound("->", vec!(cond.into(), then.into()));
+                match otherwise {
+                    Some(otherwise) => compound(";", vec!(term, otherwise.into())),
+                    None => term,
+                }
+            },
+            ExprKind::SoftCut(cond, then, otherwise) => {
+                let term = compound("*->", vec!(cond.into(), then.into()));
+                match otherwise {
+                    Some(otherwise) => compound(";", vec!(term, otherwise.into())),
+                    None => term,
+                }
+            },
+        }
+    }
+}
+
+impl Drop for TermKind {
+    fn drop(&mut self) {
+        // Unlink the spine of a list one cell at a time, so that dropping a long list doesn't overflow the stack.  Only
+        // the cells which aren't shared with another term are freed
+        let mut tail = match self {
+            TermKind::List(_, tail) if matches!(**tail, TermKind::List(_, _)) => mem::replace(tail, Rc::new(TermKind::EmptyList)),
+            _ => return,
+        };
+
+        while let Ok(mut cell) = Rc::try_unwrap(tail) {
+            tail = match &mut cell {
+                TermKind::List(_, next) if matches!(**next, TermKind::List(_, _)) => mem::replace(next, Rc::new(TermKind::EmptyList)),
+                _ => break,
+            };
+        }
+    }
+}
+
 impl TermKind {
     pub fn get_args(&self) -> Option<&[Term]> {
         match self {
@@ -48,42 +136,65 @@ impl TermKind {
             _ => None,
         }
     }
+
+    /// Returns the name and arity of an atom or compound term
+    pub fn indicator(&self) -> Option<(Atom, usize)> {
+        match self {
+            TermKind::Atom(name) => Some((*name, 0)),
+            TermKind::Compound(name, args) => Some((*name, args.len())),
+            _ => None,
+        }
+    }
 }
 
 
 #[allow(dead_code)]
 pub fn variable(name: &str) -> Term {
-    Box::new(TermKind::Var(name.to_string()))
+    Rc::new(TermKind::Var(Variable { name: Atom::new(name), id: 0 }))
 }
 
 #[allow(dead_code)]
 pub fn atom(name: &str) -> Term {
-    Box::new(TermKind::Atom(name.to_string()))
+    Rc::new(TermKind::Atom(Atom::new(name)))
 }
 
 #[allow(dead_code)]
 pub fn integer(num: i64) -> Term {
-    Box::new(TermKind::Integer(num))
+    Rc::new(TermKind::Integer(num))
+}
+
+/// Create an integer term, which is stored as a small integer if it fits in one
+#[allow(dead_code)]
+pub fn big_integer(num: BigInt) -> Term {
+    match num.to_i64() {
+        Some(num) => Rc::new(TermKind::Integer(num)),
+        None => Rc::new(TermKind::BigInteger(num)),
+    }
+}
+
+#[allow(dead_code)]
+pub fn float(num: f64) -> Term {
+    Rc::new(TermKind::Float(num))
 }
 
 #[allow(dead_code)]
 pub fn string(string: String) -> Term {
-    Box::new(TermKind::String(string))
+    Rc::new(TermKind::String(string))
 }
 
 #[allow(dead_code)]
 pub fn compound(name: &str, args: Vec<Term>) -> Term {
-    Box::new(TermKind::Compound(name.to_string(), args))
+    Rc::new(TermKind::Compound(Atom::new(name), args))
 }
 
 #[allow(dead_code)]
 pub fn empty_list() -> Term {
-    Box::new(TermKind::EmptyList)
+    Rc::new(TermKind::EmptyList)
 }
 
 #[allow(dead_code)]
 pub fn cons_list(term: Term, tail: Term) -> Term {
-    Box::new(TermKind::List(term, tail))
+    Rc::new(TermKind::List(term, tail))
 }
 
 #[allow(dead_code)]
@@ -91,6 +202,21 @@ pub fn conjunct(expr1: impl Into<Expr>, expr2: impl Into<Expr>) -> Expr {
     Box::new(ExprKind::Conjunct(expr1.into(), expr2.into()))
 }
 
+#[allow(dead_code)]
+pub fn disjunct(expr1: impl Into<Expr>, expr2: impl Into<Expr>) -> Expr {
+    Box::new(ExprKind::Disjunct(expr1.into(), expr2.into()))
+}
+
+#[allow(dead_code)]
+pub fn if_then_else(cond: impl Into<Expr>, then: impl Into<Expr>, otherwise: Option<Expr>) -> Expr {
+    Box::new(ExprKind::IfThenElse(cond.into(), then.into(), otherwise))
+}
+
+#[allow(dead_code)]
+pub fn soft_cut(cond: impl Into<Expr>, then: impl Into<Expr>, otherwise: Option<Expr>) -> Expr {
+    Box::new(ExprKind::SoftCut(cond.into(), then.into(), otherwise))
+}
+
 #[allow(dead_code)]
 pub fn fact(term: Term) -> Clause {
     Clause::Fact(term)
@@ -102,13 +228,100 @@ pub fn rule(lhs: Term, rhs: impl Into<Expr>) -> Clause {
 }
 
 
+/// Replace each variable in a term with the term returned by the given function.  Any parts of the term which don't
+/// contain variables are shared with the original term rather than copied
+pub fn map_variables<F: FnMut(Variable) -> Term>(term: &Term, map: &mut F) -> Term {
+    match &**term {
+        TermKind::Compound(name, args) => {
+            let mapped: Vec<Term> = args.iter().map(|arg| map_variables(arg, map)).collect();
+            match mapped.iter().zip(args.iter()).all(|(new, old)| Rc::ptr_eq(new, old)) {
+                true => term.clone(),
+                false => Rc::new(TermKind::Compound(*name, mapped)),
+            }
+        },
+        TermKind::List(head, tail) => {
+            let (new_head, new_tail) = (map_variables(head, map), map_variables(tail, map));
+            match Rc::ptr_eq(&new_head, head) && Rc::ptr_eq(&new_tail, tail) {
+                true => term.clone(),
+                false => Rc::new(TermKind::List(new_head, new_tail)),
+            }
+        },
+        TermKind::Var(var) => map(*var),
+        _ => term.clone(),
+    }
+}
+
+/// Collect each of the terms in an expression
+pub fn expr_terms(expr: &Expr, terms: &mut Vec<Term>) {
+    match &**expr {
+        ExprKind::Term(term) => terms.push(term.clone()),
+        ExprKind::Conjunct(expr1, expr2) | ExprKind::Disjunct(expr1, expr2) => {
+            expr_terms(expr1, terms);
+            expr_terms(expr2, terms);
+        },
+        ExprKind::IfThenElse(cond, then, otherwise) | ExprKind::SoftCut(cond, then, otherwise) => {
+            expr_terms(cond, terms);
+            expr_terms(then, terms);
+            if let Some(otherwise) = otherwise {
+                expr_terms(otherwise, terms);
+            }
+        },
+    }
+}
+
+/// Replace each variable in an expression with the term returned by the given function
+pub fn map_expr_variables<F: FnMut(Variable) -> Term>(expr: &Expr, map: &mut F) -> Expr {
+    Box::new(match &**expr {
+        ExprKind::Term(term) => {
+            ExprKind::Term(map_variables(term, map))
+        },
+        ExprKind::Conjunct(expr1, expr2) => {
+            ExprKind::Conjunct(map_expr_variables(expr1, map), map_expr_variables(expr2, map))
+        },
+        ExprKind::Disjunct(expr1, expr2) => {
+            ExprKind::Disjunct(map_expr_variables(expr1, map), map_expr_variables(expr2, map))
+        },
+        ExprKind::IfThenElse(cond, then, otherwise) => {
+            let (cond, then) = (map_expr_variables(cond, map), map_expr_variables(then, map));
+            let otherwise = otherwise.as_ref().map(|expr| map_expr_variables(expr, map));
+            ExprKind::IfThenElse(cond, then, otherwise)
+        },
+        ExprKind::SoftCut(cond, then, otherwise) => {
+            let (cond, then) = (map_expr_variables(cond, map), map_expr_variables(then, map));
+            let otherwise = otherwise.as_ref().map(|expr| map_expr_variables(expr, map));
+            ExprKind::SoftCut(cond, then, otherwise)
+        },
+    })
+}
+
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Variables which have been renamed are shown by their id alone, since their names are meaningless outside
+        // of the clause they came from
+        match self.id {
+            0 => write!(f, "{}", self.name),
+            id => write!(f, "_G{}", id),
+        }
+    }
+}
+
 impl fmt::Display for TermKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             TermKind::EmptyList => write!(f, "[]"),
             TermKind::Atom(s) => write!(f, "{}", s),
-            TermKind::Var(s) => write!(f, "{}", s),
+            TermKind::Var(var) => write!(f, "{}", var),
             TermKind::Integer(num) => write!(f, "{}", num),
+            TermKind::BigInteger(num) => write!(f, "{}", num),
+            TermKind::Float(num) => {
+                // Always print a decimal point, so a float can be told apart from an integer, like `1.0e-5` or `2.0`
+                let text = format!("{:?}", num);
+                match text.find('e') {
+                    Some(i) if !text[..i].contains('.') => write!(f, "{}.0{}", &text[..i], &text[i..]),
+                    _ => write!(f, "{}", text),
+                }
+            },
             TermKind::String(string) => write!(f, "\"{}\"", string),
             TermKind::Compound(s, args) => {
                 let args = args.iter().map(|arg| format!("{}", arg)).collect::<Vec<String>>().join(", ");
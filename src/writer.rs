@@ -0,0 +1,247 @@
+use std::fmt::Write;
+use std::collections::HashMap;
+
+use crate::tree::{ Term, TermKind, Variable, variable, map_variables };
+use crate::atoms::Atom;
+use crate::solver::Database;
+use crate::operators::Operators;
+use crate::parser::{ ARGUMENT_PRIORITY, is_word, is_operator };
+
+
+/// The highest priority of the head of a clause, which is just below `:-`
+const HEAD_PRIORITY: usize = 1199;
+/// The highest priority of a goal in the body of a clause that's written without brackets, which is just below `,`
+const GOAL_PRIORITY: usize = 999;
+
+/// Writes terms in the form they would be read in a program, using operator notation for the operators in the given
+/// table, with brackets where an operand's priority is too high, and quoting atoms which wouldn't otherwise be read
+/// back as the same atom
+pub struct Writer<'a> {
+    operators: &'a Operators,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(operators: &'a Operators) -> Self {
+        Writer { operators }
+    }
+
+    /// Returns the term as it would be written where a term of up to the given priority is expected
+    pub fn write(&self, term: &Term, max: usize) -> String {
+        let mut text = String::new();
+        self.write_term(term, max, &mut text);
+        text
+    }
+
+    fn write_term(&self, term: &Term, max: usize, out: &mut String) {
+        match &**term {
+            TermKind::Atom(name) => {
+                // An atom which is an operator is bracketed when it's an operand, so it isn't read as the operator
+                let priority = self.atom_priority(name);
+                bracketed(priority > max, out, |out| out.push_str(&quote_atom(name)));
+            },
+            TermKind::Compound(name, args) => self.write_compound(name.as_str(), args, max, out),
+            TermKind::List(_, _) => self.write_list(term, out),
+            TermKind::String(text) => out.push_str(&quoted(text, '"')),
+            // Other terms are written the same as they're displayed
+            _ => { let _ = write!(out, "{}", term); },
+        }
+    }
+
+    fn write_compound(&self, name: &str, args: &[Term], max: usize, out: &mut String) {
+        match args {
+            [left, right] => if let Some(op) = self.operators.infix(name) {
+                let (left_max, right_max) = op.kind.argument_priorities(op.priority);
+                bracketed(op.priority > max, out, |out| {
+                    self.write_term(left, left_max, out);
+                    match name {
+                        "," => out.push_str(", "),
+                        _ => { let _ = write!(out, " {} ", quote_atom(name)); },
+                    }
+                    self.write_term(right, right_max, out);
+                });
+                return;
+            },
+            // A minus sign followed by a number would be read as a negative number, so that's written canonically
+            [operand] if !(name == "-" && is_number(operand)) => {
+                if let Some(op) = self.operators.prefix(name) {
+                    let (_, operand_max) = op.kind.argument_priorities(op.priority);
+                    bracketed(op.priority > max, out, |out| {
+                        let _ = write!(out, "{} ", quote_atom(name));
+                        self.write_term(operand, operand_max, out);
+                    });
+                    return;
+                }
+
+                if let Some(op) = self.operators.postfix(name) {
+                    let (operand_max, _) = op.kind.argument_priorities(op.priority);
+                    bracketed(op.priority > max, out, |out| {
+                        self.write_term(operand, operand_max, out);
+                        let _ = write!(out, " {}", quote_atom(name));
+                    });
+                    return;
+                }
+            },
+            _ => { },
+        }
+
+        out.push_str(&quote_atom(name));
+        out.push('(');
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            self.write_argument(arg, out);
+        }
+        out.push(')');
+    }
+
+    fn write_list(&self, list: &Term, out: &mut String) {
+        out.push('[');
+        let mut rest = list;
+        let mut first = true;
+        while let TermKind::List(head, tail) = &**rest {
+            if !first {
+                out.push_str(", ");
+            }
+            first = false;
+            self.write_argument(head, out);
+            rest = tail;
+        }
+
+        if !matches!(&**rest, TermKind::EmptyList) {
+            out.push_str(" | ");
+            self.write_argument(rest, out);
+        }
+        out.push(']');
+    }
+
+    /// Write an argument of a compound term or an item of a list, where an atom doesn't need brackets even if it's an
+    /// operator, since it can't have an operand
+    fn write_argument(&self, arg: &Term, out: &mut String) {
+        match &**arg {
+            TermKind::Atom(name) => out.push_str(&quote_atom(name)),
+            _ => self.write_term(arg, ARGUMENT_PRIORITY, out),
+        }
+    }
+
+    /// Returns the highest priority of any operator with the given name, or 0 if it isn't an operator
+    fn atom_priority(&self, name: &str) -> usize {
+        [self.operators.prefix(name), self.operators.infix(name), self.operators.postfix(name)]
+            .iter()
+            .flatten()
+            .map(|op| op.priority)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Returns the name given to the nth distinct variable of a clause when it's listed, in the order A to Z, then A1
+/// to Z1, and so on
+fn listing_variable_name(n: usize) -> String {
+    let letter = (b'A' + (n % 26) as u8) as char;
+    match n / 26 {
+        0 => letter.to_string(),
+        round => format!("{}{}", letter, round),
+    }
+}
+
+/// Format a clause as it would appear in a program, with each goal of a rule's body on its own line, so that it reads
+/// back as the same clause with the given operators
+pub fn format_clause(head: &Term, body: Option<Term>, operators: &Operators) -> String {
+    let mut names: HashMap<Variable, Term> = HashMap::new();
+    let mut rename = |var: Variable| {
+        let n = names.len();
+        names.entry(var).or_insert_with(|| variable(&listing_variable_name(n))).clone()
+    };
+
+    let writer = Writer::new(operators);
+    let head = map_variables(head, &mut rename);
+    let body = match body {
+        Some(body) if !matches!(&*body, TermKind::Atom(name) if name == "true") => map_variables(&body, &mut rename),
+        _ => return format!("{}.", writer.write(&head, HEAD_PRIORITY)),
+    };
+
+    let mut goals = vec!();
+    let mut rest = &body;
+    while let TermKind::Compound(name, args) = &**rest {
+        if name != "," || args.len() != 2 {
+            break;
+        }
+        goals.push(writer.write(&args[0], GOAL_PRIORITY));
+        rest = &args[1];
+    }
+    goals.push(writer.write(rest, GOAL_PRIORITY));
+    format!("{} :-\n    {}.", writer.write(&head, HEAD_PRIORITY), goals.join(",\n    "))
+}
+
+/// Format the clauses of each of the given predicates in source form, with a directive before the clauses of each
+/// dynamic predicate
+pub fn format_listing(db: &Database, indicators: &[(Atom, usize)]) -> String {
+    let operators = db.operators();
+    let mut listing = String::new();
+    for (name, arity) in indicators {
+        let (dynamic, clauses) = match db.clauses(*name, *arity) {
+            Some(predicate) => predicate,
+            None => continue,
+        };
+
+        if dynamic {
+            listing += &format!(":- dynamic {}/{}.\n\n", quote_atom(name), arity);
+        }
+        for clause in clauses.iter() {
+            listing += &format_clause(&clause.head, clause.body.clone().map(Term::from), &operators);
+            listing.push('\n');
+        }
+        listing.push('\n');
+    }
+    listing
+}
+
+/// Write something, in brackets if it's needed
+fn bracketed(needed: bool, out: &mut String, write: impl FnOnce(&mut String)) {
+    if needed {
+        out.push('(');
+    }
+    write(out);
+    if needed {
+        out.push(')');
+    }
+}
+
+fn is_number(term: &Term) -> bool {
+    matches!(&**term, TermKind::Integer(_) | TermKind::BigInteger(_) | TermKind::Float(_))
+}
+
+/// Returns the atom as it would be written in a program, which is in quotes unless it's a name starting with a
+/// lowercase letter, a sequence of symbol characters, or one of the atoms which are a token on their own
+pub fn quote_atom(name: &str) -> String {
+    let plain = match name.chars().next() {
+        Some(ch) if ch.is_ascii_lowercase() => name.chars().all(is_word),
+        Some(_) if ["!", ";", "_"].contains(&name) => true,
+        // A single period would be read as the end of the clause
+        Some(_) => name != "." && name.chars().all(is_operator),
+        None => false,
+    };
+
+    match plain {
+        true => name.to_string(),
+        false => quoted(name, '\''),
+    }
+}
+
+/// Returns the text in the given quotes, with the quote doubled and backslashes and control characters escaped, the
+/// way the lexer reads them
+fn quoted(text: &str, quote: char) -> String {
+    let mut quoted = String::from(quote);
+    for ch in text.chars() {
+        match ch {
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            ch if ch == quote => { quoted.push(quote); quoted.push(quote); },
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push(quote);
+    quoted
+}